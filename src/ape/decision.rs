@@ -0,0 +1,144 @@
+use super::structs::{Context, PermCheck, ResWithPerm};
+use crate::error::ApeError;
+use aruna_cache::structs::Resource;
+use diesel_ulid::DieselUlid;
+use serde::Deserialize;
+use serde::Serialize;
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+pub enum Verdict {
+    Granted,
+    Denied(String),
+}
+
+/// A serializable trace of a single authorization decision
+///
+/// - granted_by: The grant that directly matched the requested resource
+/// - denied_by: Grants for the requested resource with an insufficient level
+/// - sa_shortcut: Access was granted because the resource allows service accounts
+/// - public_shortcut: Access was granted because READ is enough for public resources
+/// - constraints: Grants of ancestors with a sufficient level
+/// - inherited_from: The most specific ancestor that granted access
+///
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+pub struct Decision {
+    pub context: Context,
+    pub user_id: Option<DieselUlid>,
+    pub token_id: Option<DieselUlid>,
+    pub granted_by: Option<ResWithPerm>,
+    pub denied_by: Vec<ResWithPerm>,
+    pub sa_shortcut: bool,
+    pub public_shortcut: bool,
+    pub constraints: Vec<Resource>,
+    pub inherited_from: Option<Resource>,
+    pub verdict: Verdict,
+}
+
+impl Decision {
+    pub fn new(context: Context) -> Self {
+        Decision {
+            context,
+            user_id: None,
+            token_id: None,
+            granted_by: None,
+            denied_by: vec![],
+            sa_shortcut: false,
+            public_shortcut: false,
+            constraints: vec![],
+            inherited_from: None,
+            verdict: Verdict::Denied("Not evaluated".to_string()),
+        }
+    }
+
+    pub fn is_granted(&self) -> bool {
+        self.verdict == Verdict::Granted
+    }
+
+    pub(crate) fn add_check(&mut self, check: PermCheck) {
+        self.sa_shortcut = check.sa_shortcut;
+        self.public_shortcut = check.public_shortcut;
+        self.granted_by = check.granted_by;
+        self.denied_by = check.denied_by;
+        self.constraints = check.constraints.into_iter().collect();
+        self.constraints.sort();
+    }
+
+    pub(crate) fn grant(mut self) -> Self {
+        self.verdict = Verdict::Granted;
+        self
+    }
+
+    pub(crate) fn deny(mut self, err: ApeError) -> Self {
+        self.verdict = Verdict::Denied(err.to_string());
+        self
+    }
+}
+
+/// Results of a batch of contexts, in the order of the requested contexts
+#[derive(Debug)]
+pub struct BatchDecision {
+    pub user_id: Option<DieselUlid>,
+    pub results: Vec<Result<(), ApeError>>,
+}
+
+impl BatchDecision {
+    /// Bitmap of the granted contexts
+    pub fn granted(&self) -> Vec<bool> {
+        self.results.iter().map(|result| result.is_ok()).collect()
+    }
+
+    pub fn all_granted(&self) -> bool {
+        self.results.iter().all(|result| result.is_ok())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ape::permission_index::PermissionIndex;
+    use crate::ape::structs::{AllUserPermission, PermissionLevels};
+    use aruna_rust_api::api::storage::models::v2::PermissionLevel;
+
+    #[test]
+    fn test_decision_trace() {
+        let granted = DieselUlid::generate();
+        let denied = DieselUlid::generate();
+        let perms = AllUserPermission {
+            perms: vec![
+                ResWithPerm::Collection((granted, PermissionLevel::Write)),
+                ResWithPerm::Object((denied, PermissionLevel::Read)),
+            ],
+            ..Default::default()
+        };
+
+        let ctx = Context::res_col(granted, PermissionLevels::WRITE, false);
+        let mut decision = Decision::new(ctx.clone());
+        decision.add_check(
+            PermissionIndex::from(&perms)
+                .check_single_perm(&ctx.resource().unwrap(), ctx.resource_permission().unwrap()),
+        );
+        let decision = decision.grant();
+        assert!(decision.is_granted());
+        assert_eq!(
+            decision.granted_by,
+            Some(ResWithPerm::Collection((granted, PermissionLevel::Write)))
+        );
+        assert!(decision.constraints.is_empty());
+
+        let ctx = Context::res_obj(denied, PermissionLevels::WRITE, false);
+        let mut decision = Decision::new(ctx.clone());
+        decision.add_check(
+            PermissionIndex::from(&perms)
+                .check_single_perm(&ctx.resource().unwrap(), ctx.resource_permission().unwrap()),
+        );
+        assert_eq!(
+            decision.denied_by,
+            vec![ResWithPerm::Object((denied, PermissionLevel::Read))]
+        );
+        assert_eq!(decision.constraints, vec![Resource::Collection(granted)]);
+        assert!(perms.compare_ctx(ctx.clone()).unwrap().is_some());
+        let decision = decision.deny(ApeError::HierarchyConstraintFailed(ctx));
+        assert!(!decision.is_granted());
+        assert!(serde_json::to_string(&decision).is_ok());
+    }
+}
@@ -0,0 +1,386 @@
+use super::hierarchy::Hierarchy;
+use super::permission_cache::fingerprint;
+use super::policy_evaluator::get_data_class;
+use super::structs::Context;
+use crate::error::ApeError;
+use crate::token::token_cache::{hash_token, TokenHash};
+use crate::token::token_handler::{check_token_record, TokenInfo};
+use aruna_cache::cache::Cache;
+use aruna_cache::structs::Resource;
+use aruna_rust_api::api::storage::models::v2::DataClass;
+use diesel_ulid::DieselUlid;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+type Key = (TokenHash, Context);
+
+/// Counters of the decision cache
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct DecisionCacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub entries: usize,
+}
+
+/// State of the cache a granted decision was based on,
+/// must be read before the permissions are evaluated
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Snapshot {
+    /// Fingerprint of the user, see `user_fingerprint`
+    pub user: Option<u64>,
+    /// Data class of the requested resource
+    pub data_class: Option<DataClass>,
+    /// Ancestor the access was inherited from
+    pub ancestor: Option<Resource>,
+}
+
+/// Granted decision together with the snapshot it was based on
+#[derive(Clone)]
+struct Grant {
+    user_id: Option<DieselUlid>,
+    token_id: Option<DieselUlid>,
+    jti: Option<DieselUlid>,
+    snapshot: Snapshot,
+    expires: Instant,
+}
+
+struct Entry {
+    grant: Grant,
+    tick: u64,
+}
+
+#[derive(Default)]
+struct Entries {
+    map: HashMap<Key, Entry>,
+    /// Entries by their last use, least recently used first
+    lru: BTreeMap<u64, Key>,
+    tick: u64,
+}
+
+impl Entries {
+    fn remove(&mut self, key: &Key) {
+        if let Some(entry) = self.map.remove(key) {
+            self.lru.remove(&entry.tick);
+        }
+    }
+}
+
+/// Bounded LRU cache of granted decisions keyed by the SHA-256 hash of the token and the context
+///
+/// Entries expire after the TTL or with the token, whichever comes first.
+/// Every hit checks the revocation list and compares the snapshot with the cache:
+/// any update of the user, a new data class of the resource or a removed path
+/// to the granting ancestor drops the entry. Hits are validated on a copy of the
+/// entry after the lock is released, concurrent checks never wait on each other.
+pub(crate) struct DecisionCache {
+    capacity: usize,
+    ttl: Duration,
+    entries: Mutex<Entries>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl DecisionCache {
+    pub fn new(capacity: usize, ttl: Duration) -> Self {
+        DecisionCache {
+            capacity,
+            ttl,
+            entries: Mutex::default(),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns the user id of a cached grant that is still valid
+    pub fn get(
+        &self,
+        hierarchy: &Hierarchy,
+        token: &str,
+        ctx: &Context,
+        is_revoked: impl Fn(DieselUlid) -> bool,
+    ) -> Result<Option<Option<DieselUlid>>, ApeError> {
+        let key = (hash_token(token), ctx.clone());
+        let cached = self
+            .lock()?
+            .map
+            .get(&key)
+            .map(|entry| (entry.grant.clone(), entry.tick));
+        let result = match cached {
+            Some((grant, last)) => {
+                let valid = is_valid(hierarchy, ctx, &grant, &is_revoked);
+                let mut entries = self.lock()?;
+                // Entries that were replaced during the validation are kept as they are
+                if entries
+                    .map
+                    .get(&key)
+                    .is_some_and(|entry| entry.tick == last)
+                {
+                    if valid {
+                        entries.tick += 1;
+                        let tick = entries.tick;
+                        let entry = entries.map.get_mut(&key).expect("Entry exists");
+                        entry.tick = tick;
+                        entries.lru.remove(&last);
+                        entries.lru.insert(tick, key);
+                    } else {
+                        entries.remove(&key);
+                    }
+                }
+                valid.then_some(grant.user_id)
+            }
+            None => None,
+        };
+        match result {
+            Some(_) => self.hits.fetch_add(1, Ordering::Relaxed),
+            None => self.misses.fetch_add(1, Ordering::Relaxed),
+        };
+        Ok(result)
+    }
+
+    /// Caches a granted decision of the token
+    pub fn insert(
+        &self,
+        token: &str,
+        ctx: Context,
+        info: &TokenInfo,
+        snapshot: Snapshot,
+        token_exp: Option<u64>,
+    ) -> Result<(), ApeError> {
+        if self.capacity == 0 {
+            return Ok(());
+        }
+        let mut expires = Instant::now() + self.ttl;
+        if let Some(exp) = token_exp {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_err(|e| ApeError::Internal(e.to_string()))?
+                .as_secs();
+            expires = expires.min(Instant::now() + Duration::from_secs(exp.saturating_sub(now)));
+        }
+
+        let key = (hash_token(token), ctx);
+        let mut entries = self.lock()?;
+        entries.remove(&key);
+        while entries.map.len() >= self.capacity {
+            let Some((_, oldest)) = entries.lru.pop_first() else {
+                break;
+            };
+            entries.map.remove(&oldest);
+        }
+        entries.tick += 1;
+        let tick = entries.tick;
+        entries.lru.insert(tick, key.clone());
+        entries.map.insert(
+            key,
+            Entry {
+                grant: Grant {
+                    user_id: info.user_id,
+                    token_id: info.token_id,
+                    jti: info.jti,
+                    snapshot,
+                    expires,
+                },
+                tick,
+            },
+        );
+        Ok(())
+    }
+
+    /// Drops all decisions of the user
+    pub fn remove_user(&self, user_id: DieselUlid) -> Result<(), ApeError> {
+        let mut entries = self.lock()?;
+        let keys: Vec<Key> = entries
+            .map
+            .iter()
+            .filter(|(_, entry)| entry.grant.user_id == Some(user_id))
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in keys {
+            entries.remove(&key);
+        }
+        Ok(())
+    }
+
+    pub fn clear(&self) -> Result<(), ApeError> {
+        *self.lock()? = Entries::default();
+        Ok(())
+    }
+
+    pub fn stats(&self) -> Result<DecisionCacheStats, ApeError> {
+        Ok(DecisionCacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            entries: self.lock()?.map.len(),
+        })
+    }
+
+    fn lock(&self) -> Result<MutexGuard<'_, Entries>, ApeError> {
+        self.entries
+            .lock()
+            .map_err(|_| ApeError::Internal("Poisoned decision cache".to_string()))
+    }
+}
+
+/// Fingerprint of the cached user, changes with every update of the user
+pub(crate) fn user_fingerprint(cache: &Cache, user_id: DieselUlid) -> Option<u64> {
+    cache
+        .user_cache
+        .get(&user_id)
+        .map(|user| fingerprint(&*user))
+}
+
+/// Grants are valid until they expire, the token is revoked, the snapshot
+/// no longer matches the cache or the stored token is gone
+fn is_valid(
+    hierarchy: &Hierarchy,
+    ctx: &Context,
+    entry: &Grant,
+    is_revoked: impl Fn(DieselUlid) -> bool,
+) -> bool {
+    if entry.expires <= Instant::now()
+        || entry.token_id.into_iter().chain(entry.jti).any(is_revoked)
+    {
+        return false;
+    }
+    if let Some(res) = ctx.resource() {
+        if get_data_class(hierarchy.cache, &res) != entry.snapshot.data_class {
+            return false;
+        }
+        if let Some(ancestor) = &entry.snapshot.ancestor {
+            if hierarchy
+                .find_granting_ancestor(&res, |r| r == ancestor)
+                .is_none()
+            {
+                return false;
+            }
+        }
+    }
+    let Some(user_id) = entry.user_id else {
+        return true;
+    };
+    let Some(user) = hierarchy.cache.user_cache.get(&user_id) else {
+        return entry.snapshot.user.is_none();
+    };
+    if Some(fingerprint(&*user)) != entry.snapshot.user {
+        return false;
+    }
+    match entry.token_id {
+        Some(token_id) => check_token_record(&user, token_id).is_ok(),
+        None => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ape::hierarchy::tests::relate;
+    use crate::ape::structs::PermissionLevels;
+    use aruna_rust_api::api::storage::models::v2::{generic_resource, ExternalId, Object, User};
+
+    #[test]
+    fn test_decision_cache() {
+        let cache = Cache::new();
+        let user = User {
+            id: DieselUlid::generate().to_string(),
+            external_ids: vec![ExternalId::default()],
+            ..Default::default()
+        };
+        let user_id: DieselUlid = user.id.parse().unwrap();
+        cache.add_or_update_user(user.clone()).unwrap();
+        let ctx = |id| Context::res_obj(id, PermissionLevels::READ, false);
+        let (a, b, c) = (
+            DieselUlid::generate(),
+            DieselUlid::generate(),
+            DieselUlid::generate(),
+        );
+        let info = TokenInfo {
+            user_id: Some(user_id),
+            jti: Some(DieselUlid::generate()),
+            ..Default::default()
+        };
+        let snapshot = Snapshot {
+            user: user_fingerprint(&cache, user_id),
+            ..Default::default()
+        };
+
+        let decisions = DecisionCache::new(2, Duration::from_secs(60));
+        let insert = |id, snapshot: Snapshot| {
+            decisions
+                .insert("token", ctx(id), &info, snapshot, None)
+                .unwrap()
+        };
+        let get = |token, id| decisions.get(&Hierarchy::new(&cache), token, &ctx(id), |_| false);
+        assert_eq!(get("token", a).unwrap(), None);
+        insert(a, snapshot.clone());
+        insert(b, snapshot.clone());
+        assert_eq!(get("token", a).unwrap(), Some(Some(user_id)));
+        assert_eq!(get("other", a).unwrap(), None);
+
+        // The least recently used entry is evicted
+        insert(c, snapshot.clone());
+        assert_eq!(get("token", b).unwrap(), None);
+        assert!(get("token", a).unwrap().is_some());
+        assert_eq!(
+            decisions.stats().unwrap(),
+            DecisionCacheStats {
+                hits: 2,
+                misses: 3,
+                entries: 2,
+            }
+        );
+
+        // Revoked tokens are never served
+        let revoked = |id| Some(id) == info.jti;
+        assert_eq!(
+            decisions
+                .get(&Hierarchy::new(&cache), "token", &ctx(a), revoked)
+                .unwrap(),
+            None
+        );
+
+        // A new data class of the resource invalidates the decision
+        insert(a, snapshot.clone());
+        cache.object_cache.insert(
+            Resource::Object(a),
+            generic_resource::Resource::Object(Object {
+                id: a.to_string(),
+                data_class: DataClass::Confidential as i32,
+                ..Default::default()
+            }),
+        );
+        assert_eq!(get("token", a).unwrap(), None);
+
+        // Removed paths to the granting ancestor invalidate the decision
+        let project = Resource::Project(DieselUlid::generate());
+        relate(&cache, &project, &Resource::Object(b));
+        let inherited = Snapshot {
+            ancestor: Some(project.clone()),
+            ..snapshot.clone()
+        };
+        insert(b, inherited);
+        assert!(get("token", b).unwrap().is_some());
+        cache.relations_cache.remove(&project);
+        assert_eq!(get("token", b).unwrap(), None);
+
+        // Updates of the user invalidate all of its decisions
+        insert(c, snapshot.clone());
+        let mut updated = user.clone();
+        updated.display_name = "changed".to_string();
+        cache.add_or_update_user(updated).unwrap();
+        assert_eq!(get("token", c).unwrap(), None);
+
+        // Expired tokens are never served
+        decisions
+            .insert(
+                "expired",
+                ctx(a),
+                &TokenInfo::default(),
+                Snapshot::default(),
+                Some(0),
+            )
+            .unwrap();
+        assert_eq!(get("expired", a).unwrap(), None);
+    }
+}
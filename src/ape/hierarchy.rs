@@ -0,0 +1,207 @@
+use aruna_cache::cache::Cache;
+use aruna_cache::structs::Resource;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Maximum number of ancestors between an object and its project
+const MAX_DEPTH: usize = 3;
+
+/// Child to parents index of the relations of the cache
+pub(crate) struct ParentIndex {
+    parents: HashMap<Resource, Vec<Resource>>,
+    built: Instant,
+}
+
+impl ParentIndex {
+    pub fn build(cache: &Cache) -> Self {
+        let mut parents: HashMap<Resource, Vec<Resource>> = HashMap::new();
+        for entry in cache.relations_cache.iter() {
+            for child in entry.value().iter() {
+                parents
+                    .entry(child.key().clone())
+                    .or_default()
+                    .push(entry.key().clone());
+            }
+        }
+        ParentIndex {
+            parents,
+            built: Instant::now(),
+        }
+    }
+
+    pub fn age(&self) -> Duration {
+        self.built.elapsed()
+    }
+}
+
+/// Resource hierarchy of the cache
+///
+/// Parents are looked up in a `ParentIndex` that is shared across many lookups,
+/// indexed parents are only followed while the relation still exists in the cache.
+/// Lookups without a granting ancestor fall back to the live relations of the cache,
+/// so relations added after the index was built are never missed.
+pub(crate) struct Hierarchy<'a> {
+    pub(crate) cache: &'a Cache,
+    index: Arc<ParentIndex>,
+}
+
+impl<'a> Hierarchy<'a> {
+    /// Indexes the current relations of the cache
+    #[cfg(test)]
+    pub fn new(cache: &'a Cache) -> Self {
+        Hierarchy::indexed(cache, Arc::new(ParentIndex::build(cache)))
+    }
+
+    pub fn indexed(cache: &'a Cache, index: Arc<ParentIndex>) -> Self {
+        Hierarchy { cache, index }
+    }
+
+    /// Walks the ancestors of `res` level by level, starting with its direct parents,
+    /// and returns the most specific ancestor that grants access
+    ///
+    /// All parents are followed, resources that are part of multiple
+    /// collections or datasets are granted by any of their ancestors.
+    pub fn find_granting_ancestor(
+        &self,
+        res: &Resource,
+        grants: impl Fn(&Resource) -> bool,
+    ) -> Option<Resource> {
+        walk_ancestors(res, &grants, |children| self.get_direct_parents(children))
+            .or_else(|| walk_ancestors(res, &grants, |children| self.get_live_parents(children)))
+    }
+
+    /// Returns the direct children of the resource
+    pub fn get_children(&self, res: &Resource) -> Vec<Resource> {
+        self.cache
+            .relations_cache
+            .get(res)
+            .map(|children| children.iter().map(|child| child.key().clone()).collect())
+            .unwrap_or_default()
+    }
+
+    /// Returns all indexed resources that contain at least one of the children
+    fn get_direct_parents(&self, children: &HashSet<Resource>) -> HashSet<Resource> {
+        children
+            .iter()
+            .filter_map(|child| Some(child).zip(self.index.parents.get(child)))
+            .flat_map(|(child, parents)| {
+                parents.iter().filter(move |parent| {
+                    self.cache
+                        .relations_cache
+                        .get(*parent)
+                        .is_some_and(|children| children.contains(child))
+                })
+            })
+            .cloned()
+            .collect()
+    }
+
+    /// Scans the relations of the cache for resources that contain at least one of the children
+    fn get_live_parents(&self, children: &HashSet<Resource>) -> HashSet<Resource> {
+        self.cache
+            .relations_cache
+            .iter()
+            .filter(|entry| children.iter().any(|child| entry.value().contains(child)))
+            .map(|entry| entry.key().clone())
+            .collect()
+    }
+}
+
+/// Walks the ancestors level by level and returns the first one that grants access
+fn walk_ancestors(
+    res: &Resource,
+    grants: impl Fn(&Resource) -> bool,
+    get_parents: impl Fn(&HashSet<Resource>) -> HashSet<Resource>,
+) -> Option<Resource> {
+    let mut visited = HashSet::from([res.clone()]);
+    let mut level = HashSet::from([res.clone()]);
+    for _ in 0..MAX_DEPTH {
+        let parents = get_parents(&level);
+        if let Some(granted) = parents.iter().find(|parent| grants(parent)) {
+            return Some(granted.clone());
+        }
+        level = parents
+            .into_iter()
+            .filter(|parent| visited.insert(parent.clone()))
+            .collect();
+        if level.is_empty() {
+            break;
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use diesel_ulid::DieselUlid;
+
+    /// Adds `child` to the children of `parent`
+    pub(crate) fn relate(cache: &Cache, parent: &Resource, child: &Resource) {
+        cache
+            .relations_cache
+            .entry(parent.clone())
+            .or_default()
+            .insert(child.clone());
+    }
+
+    #[test]
+    fn test_find_granting_ancestor() {
+        let cache = Cache::new();
+        let project_a = Resource::Project(DieselUlid::generate());
+        let project_b = Resource::Project(DieselUlid::generate());
+        let collection = Resource::Collection(DieselUlid::generate());
+        let dataset_a = Resource::Dataset(DieselUlid::generate());
+        let dataset_b = Resource::Dataset(DieselUlid::generate());
+        let object = Resource::Object(DieselUlid::generate());
+        relate(&cache, &project_a, &collection);
+        relate(&cache, &collection, &dataset_a);
+        relate(&cache, &project_b, &dataset_b);
+        relate(&cache, &dataset_a, &object);
+        relate(&cache, &dataset_b, &object);
+
+        let index = Arc::new(ParentIndex::build(&cache));
+        let hierarchy = Hierarchy::indexed(&cache, index.clone());
+        // The most specific ancestor wins
+        let grants = HashSet::from([project_a.clone(), collection.clone()]);
+        assert_eq!(
+            hierarchy.find_granting_ancestor(&object, |r| grants.contains(r)),
+            Some(collection.clone())
+        );
+        // All paths are followed for resources with multiple parents
+        let grants = HashSet::from([project_b.clone()]);
+        assert_eq!(
+            hierarchy.find_granting_ancestor(&object, |r| grants.contains(r)),
+            Some(project_b.clone())
+        );
+        // Grants never flow upward
+        let grants = HashSet::from([object.clone()]);
+        assert_eq!(
+            hierarchy.find_granting_ancestor(&dataset_a, |r| grants.contains(r)),
+            None
+        );
+        assert_eq!(
+            hierarchy.find_granting_ancestor(&collection, |r| r == &project_b),
+            None
+        );
+
+        // Relations added after the index was built are found in the cache
+        let project_c = Resource::Project(DieselUlid::generate());
+        let created = Resource::Object(DieselUlid::generate());
+        relate(&cache, &project_c, &dataset_b);
+        relate(&cache, &dataset_b, &created);
+        assert_eq!(
+            hierarchy.find_granting_ancestor(&created, |r| r == &project_c),
+            Some(project_c)
+        );
+
+        // Removed relations are never followed, even if they are still indexed
+        cache.relations_cache.remove(&project_b);
+        let hierarchy = Hierarchy::indexed(&cache, index);
+        assert_eq!(
+            hierarchy.find_granting_ancestor(&object, |r| r == &project_b),
+            None
+        );
+    }
+}
@@ -1,3 +1,8 @@
+pub mod decision;
+pub mod decision_cache;
+mod hierarchy;
+mod permission_cache;
+pub mod permission_index;
 pub mod permissions;
 pub mod policy_evaluator;
 pub mod structs;
@@ -0,0 +1,250 @@
+use super::permission_index::PermissionIndex;
+use super::permissions::GetPermissions;
+use crate::error::ApeError;
+use aruna_cache::cache::Cache;
+use diesel_ulid::DieselUlid;
+use serde::Serialize;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hasher;
+use std::io::Write;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+/// User and stored token of an index
+type Key = (DieselUlid, Option<DieselUlid>);
+
+/// Permission index together with the fingerprint of the attributes it was built from
+struct Entry {
+    fingerprint: u64,
+    index: Arc<PermissionIndex>,
+    built: Instant,
+    tick: u64,
+}
+
+#[derive(Default)]
+struct Entries {
+    map: HashMap<Key, Entry>,
+    /// Entries by their last use, least recently used first
+    lru: BTreeMap<u64, Key>,
+    tick: u64,
+}
+
+impl Entries {
+    fn remove(&mut self, key: &Key) {
+        if let Some(entry) = self.map.remove(key) {
+            self.lru.remove(&entry.tick);
+        }
+    }
+}
+
+/// Bounded LRU cache of permission indices by user and token
+///
+/// Indices are rebuilt after the TTL or as soon as the attributes of the cached user
+/// change. Attributes are compared by their fingerprint, which is computed before
+/// the lock is taken, so hits never compare or clone the grants under the lock.
+pub(crate) struct PermissionCache {
+    capacity: usize,
+    ttl: Duration,
+    entries: Mutex<Entries>,
+}
+
+impl PermissionCache {
+    pub fn new(capacity: usize, ttl: Duration) -> Self {
+        PermissionCache {
+            capacity,
+            ttl,
+            entries: Mutex::default(),
+        }
+    }
+
+    /// Returns the index of the user for the token, builds it if it is missing or stale
+    pub fn get_or_build(
+        &self,
+        cache: &Cache,
+        user_id: DieselUlid,
+        token_id: Option<DieselUlid>,
+    ) -> Result<Arc<PermissionIndex>, ApeError> {
+        let key = (user_id, token_id);
+        let (fingerprint, index) = {
+            let user = cache
+                .user_cache
+                .get(&user_id)
+                .ok_or(ApeError::UserNotFound(user_id))?;
+            let fingerprint = fingerprint(&user.attributes);
+            let mut entries = self.lock()?;
+            let current = entries.map.get(&key).filter(|entry| {
+                entry.built.elapsed() < self.ttl && entry.fingerprint == fingerprint
+            });
+            if let Some(index) = current.map(|entry| entry.index.clone()) {
+                entries.tick += 1;
+                let tick = entries.tick;
+                let entry = entries.map.get_mut(&key).expect("Entry exists");
+                let last = std::mem::replace(&mut entry.tick, tick);
+                entries.lru.remove(&last);
+                entries.lru.insert(tick, key);
+                return Ok(index);
+            }
+            drop(entries);
+            let index = PermissionIndex::from(&user.get_permissions(token_id)?);
+            (fingerprint, Arc::new(index))
+        };
+        self.insert(key, fingerprint, index.clone())?;
+        Ok(index)
+    }
+
+    fn insert(
+        &self,
+        key: Key,
+        fingerprint: u64,
+        index: Arc<PermissionIndex>,
+    ) -> Result<(), ApeError> {
+        if self.capacity == 0 {
+            return Ok(());
+        }
+        let mut entries = self.lock()?;
+        entries.remove(&key);
+        while entries.map.len() >= self.capacity {
+            let Some((_, oldest)) = entries.lru.pop_first() else {
+                break;
+            };
+            entries.map.remove(&oldest);
+        }
+        entries.tick += 1;
+        let tick = entries.tick;
+        entries.lru.insert(tick, key);
+        entries.map.insert(
+            key,
+            Entry {
+                fingerprint,
+                index,
+                built: Instant::now(),
+                tick,
+            },
+        );
+        Ok(())
+    }
+
+    /// Drops all indices of the user
+    pub fn remove_user(&self, user_id: DieselUlid) -> Result<(), ApeError> {
+        let mut entries = self.lock()?;
+        let keys: Vec<Key> = entries
+            .map
+            .keys()
+            .filter(|(id, _)| id == &user_id)
+            .cloned()
+            .collect();
+        for key in keys {
+            entries.remove(&key);
+        }
+        Ok(())
+    }
+
+    pub fn clear(&self) -> Result<(), ApeError> {
+        *self.lock()? = Entries::default();
+        Ok(())
+    }
+
+    fn lock(&self) -> Result<MutexGuard<'_, Entries>, ApeError> {
+        self.entries
+            .lock()
+            .map_err(|_| ApeError::Internal("Poisoned permission cache".to_string()))
+    }
+}
+
+/// Hashes the serialized value without buffering it
+pub(crate) fn fingerprint(value: &impl Serialize) -> u64 {
+    struct HashWriter(DefaultHasher);
+
+    impl Write for HashWriter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.write(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let mut writer = HashWriter(DefaultHasher::new());
+    // Serializing the generated API types never fails
+    let _ = serde_json::to_writer(&mut writer, value);
+    writer.0.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aruna_rust_api::api::storage::models::v2::{
+        permission::ResourceId, ExternalId, Permission, PermissionLevel, User, UserAttributes,
+    };
+
+    fn user_with(permissions: usize) -> User {
+        User {
+            id: DieselUlid::generate().to_string(),
+            external_ids: vec![ExternalId::default()],
+            attributes: Some(UserAttributes {
+                personal_permissions: (0..permissions)
+                    .map(|_| Permission {
+                        permission_level: PermissionLevel::Read as i32,
+                        resource_id: Some(ResourceId::ProjectId(
+                            DieselUlid::generate().to_string(),
+                        )),
+                    })
+                    .collect(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_permission_cache() {
+        let cache = Cache::new();
+        let (a, b) = (user_with(1), user_with(2));
+        let id = |user: &User| user.id.parse::<DieselUlid>().unwrap();
+        cache.add_or_update_user(a.clone()).unwrap();
+        cache.add_or_update_user(b.clone()).unwrap();
+
+        let indices = PermissionCache::new(1, Duration::from_secs(60));
+        let first = indices.get_or_build(&cache, id(&a), None).unwrap();
+        assert_eq!(first.len(), 1);
+        assert!(Arc::ptr_eq(
+            &first,
+            &indices.get_or_build(&cache, id(&a), None).unwrap()
+        ));
+
+        // Unrelated fields of the user keep the index
+        let mut renamed = a.clone();
+        renamed.display_name = "renamed".to_string();
+        cache.add_or_update_user(renamed).unwrap();
+        assert!(Arc::ptr_eq(
+            &first,
+            &indices.get_or_build(&cache, id(&a), None).unwrap()
+        ));
+
+        // New attributes rebuild the index
+        let mut updated = a.clone();
+        updated.attributes = user_with(3).attributes;
+        cache.add_or_update_user(updated).unwrap();
+        assert_eq!(indices.get_or_build(&cache, id(&a), None).unwrap().len(), 3);
+
+        // The least recently used index is evicted
+        assert_eq!(indices.get_or_build(&cache, id(&b), None).unwrap().len(), 2);
+        assert_eq!(indices.lock().unwrap().map.len(), 1);
+
+        // Indices are rebuilt after the TTL
+        let expiring = PermissionCache::new(1, Duration::ZERO);
+        let first = expiring.get_or_build(&cache, id(&b), None).unwrap();
+        assert!(!Arc::ptr_eq(
+            &first,
+            &expiring.get_or_build(&cache, id(&b), None).unwrap()
+        ));
+
+        assert!(matches!(
+            indices.get_or_build(&cache, DieselUlid::generate(), None),
+            Err(ApeError::UserNotFound(_))
+        ));
+    }
+}
@@ -0,0 +1,314 @@
+use super::structs::{
+    hierarchy_level, AllUserPermission, ApeResourcePermission, Context, PermCheck,
+    PermissionLevels, ResWithPerm, ResourceContext, TokenScope,
+};
+use crate::error::ApeError;
+use aruna_cache::structs::Resource;
+use aruna_rust_api::api::storage::models::v2::PermissionLevel;
+use diesel_ulid::DieselUlid;
+use std::collections::HashMap;
+
+/// Grants of a user indexed by resource id, split by resource type
+///
+/// Multiple grants on the same resource are merged to the highest level,
+/// all lookups are O(1) regardless of the number of grants.
+#[derive(Debug, PartialEq, Eq, Clone, Default)]
+pub struct PermissionIndex {
+    pub user_id: Option<DieselUlid>,
+    pub is_sa: bool,
+    pub is_admin: bool,
+    pub scope: TokenScope,
+    projects: HashMap<DieselUlid, PermissionLevel>,
+    collections: HashMap<DieselUlid, PermissionLevel>,
+    datasets: HashMap<DieselUlid, PermissionLevel>,
+    objects: HashMap<DieselUlid, PermissionLevel>,
+    /// Highest granted level per hierarchy level
+    max_levels: [Option<PermissionLevels>; 4],
+}
+
+impl From<&AllUserPermission> for PermissionIndex {
+    fn from(perms: &AllUserPermission) -> Self {
+        let mut index = PermissionIndex {
+            user_id: perms.user_id,
+            is_sa: perms.is_sa,
+            is_admin: perms.is_admin,
+            scope: perms.scope.clone(),
+            ..Default::default()
+        };
+        for grant in &perms.perms {
+            index.insert(grant);
+        }
+        index
+    }
+}
+
+impl PermissionIndex {
+    fn insert(&mut self, grant: &ResWithPerm) {
+        let (map, id, level) = match grant {
+            ResWithPerm::Project((id, lvl)) => (&mut self.projects, id, lvl),
+            ResWithPerm::Collection((id, lvl)) => (&mut self.collections, id, lvl),
+            ResWithPerm::Dataset((id, lvl)) => (&mut self.datasets, id, lvl),
+            ResWithPerm::Object((id, lvl)) => (&mut self.objects, id, lvl),
+        };
+        let entry = map.entry(*id).or_insert(*level);
+        if PermissionLevels::from(*level) > PermissionLevels::from(*entry) {
+            *entry = *level;
+        }
+        let max = &mut self.max_levels[hierarchy_level(&grant.resource()) as usize];
+        if max.as_ref() < Some(&PermissionLevels::from(*level)) {
+            *max = Some(PermissionLevels::from(*level));
+        }
+    }
+
+    /// Returns the highest grant on exactly this resource
+    pub fn get(&self, res: &Resource) -> Option<ResWithPerm> {
+        match res {
+            Resource::Project(id) => self
+                .projects
+                .get(id)
+                .map(|l| ResWithPerm::Project((*id, *l))),
+            Resource::Collection(id) => self
+                .collections
+                .get(id)
+                .map(|l| ResWithPerm::Collection((*id, *l))),
+            Resource::Dataset(id) => self
+                .datasets
+                .get(id)
+                .map(|l| ResWithPerm::Dataset((*id, *l))),
+            Resource::Object(id) => self.objects.get(id).map(|l| ResWithPerm::Object((*id, *l))),
+        }
+    }
+
+    /// Returns all grants, one per resource
+    pub fn grants(&self) -> impl Iterator<Item = ResWithPerm> + '_ {
+        grants(&self.projects, ResWithPerm::Project)
+            .chain(grants(&self.collections, ResWithPerm::Collection))
+            .chain(grants(&self.datasets, ResWithPerm::Dataset))
+            .chain(grants(&self.objects, ResWithPerm::Object))
+    }
+
+    pub fn len(&self) -> usize {
+        self.projects.len() + self.collections.len() + self.datasets.len() + self.objects.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Service account rights only apply to personal tokens
+    fn is_personal_sa(&self) -> bool {
+        self.is_sa && self.scope == TokenScope::Personal
+    }
+
+    /// Returns true if the grant on the ancestor is sufficient for `perm`
+    pub(crate) fn grants_inherited(
+        &self,
+        ancestor: &Resource,
+        perm: &ApeResourcePermission,
+    ) -> bool {
+        self.get(ancestor).is_some_and(|grant| {
+            perm.accepts_grant(&grant) && PermissionLevels::from(grant.level()) >= perm.level
+        })
+    }
+
+    /// Returns true if any grant above `res` could grant `perm`,
+    /// confidential resources only inherit dataset grants
+    fn may_inherit(&self, res: &Resource, perm: &ApeResourcePermission) -> bool {
+        let probe = |rank: usize| match rank {
+            0 => ResWithPerm::Project((DieselUlid::default(), PermissionLevel::Admin)),
+            1 => ResWithPerm::Collection((DieselUlid::default(), PermissionLevel::Admin)),
+            _ => ResWithPerm::Dataset((DieselUlid::default(), PermissionLevel::Admin)),
+        };
+        (0..hierarchy_level(res) as usize).any(|rank| {
+            self.max_levels[rank]
+                .as_ref()
+                .is_some_and(|max| max >= &perm.level && perm.accepts_grant(&probe(rank)))
+        })
+    }
+
+    /// Checks a requested permission on `res` against all grants of the user
+    ///
+    /// Grants flow downward from project to collection to dataset to object, never upward.
+    /// A grant on the requested resource itself is used directly, sufficient grants on
+    /// resources of a higher level become constraints, one of them must be an ancestor
+    /// of the requested resource. Grants on the same or a lower level never apply to
+    /// other resources.
+    ///
+    /// Collecting the constraints scans all grants of a higher level,
+    /// the evaluation itself only uses `check_ctx`.
+    pub(crate) fn check_single_perm(
+        &self,
+        res: &Resource,
+        perm: &ApeResourcePermission,
+    ) -> PermCheck {
+        let mut check = PermCheck::default();
+        if perm.allow_sa && self.is_personal_sa() {
+            check.sa_shortcut = true;
+            return check;
+        }
+        if perm.is_public_read() {
+            check.public_shortcut = true;
+            return check;
+        }
+        if let Some(grant) = self.get(res).filter(|grant| perm.accepts_grant(grant)) {
+            if PermissionLevels::from(grant.level()) >= perm.level {
+                check.granted_by = Some(grant);
+                return check;
+            }
+            check.denied_by.push(grant);
+        }
+        if self.may_inherit(res, perm) {
+            let higher = [
+                (
+                    &self.projects,
+                    Resource::Project as fn(DieselUlid) -> Resource,
+                ),
+                (&self.collections, Resource::Collection),
+                (&self.datasets, Resource::Dataset),
+            ];
+            check.constraints = higher
+                .into_iter()
+                .take(hierarchy_level(res) as usize)
+                .flat_map(|(map, to_res)| map.keys().map(move |id| to_res(*id)))
+                .filter(|ancestor| self.grants_inherited(ancestor, perm))
+                .collect();
+        }
+        check
+    }
+
+    /// Checks the context without the hierarchy
+    ///
+    /// Returns the requested resource and permission if access
+    /// can only be inherited from one of its ancestors.
+    pub fn check_ctx(
+        &self,
+        ctx: &Context,
+    ) -> Result<Option<(Resource, ApeResourcePermission)>, ApeError> {
+        match ctx {
+            Context::GlobalAdmin => {
+                if self.is_admin && self.scope == TokenScope::Personal {
+                    Ok(None)
+                } else {
+                    Err(ApeError::NotAdmin(ctx.clone()))
+                }
+            }
+            Context::Empty => Ok(None),
+            Context::ResourceContext(ResourceContext::Project(None)) => Ok(None),
+            Context::ResourceContext(_) => {
+                let (Some(res), Some(perm)) = (ctx.resource(), ctx.resource_permission()) else {
+                    return Ok(None);
+                };
+                if (perm.allow_sa && self.is_personal_sa()) || perm.is_public_read() {
+                    return Ok(None);
+                }
+                let direct = self.get(&res).filter(|grant| perm.accepts_grant(grant));
+                if direct.is_some_and(|grant| PermissionLevels::from(grant.level()) >= perm.level) {
+                    Ok(None)
+                } else if self.may_inherit(&res, perm) {
+                    Ok(Some((res, perm.clone())))
+                } else if self.is_personal_sa() && !perm.allow_sa {
+                    Err(ApeError::ServiceAccountNotAllowed(ctx.clone()))
+                } else {
+                    Err(ApeError::InsufficientLevel(ctx.clone()))
+                }
+            }
+            Context::User(uid) => {
+                let ok = match self.user_id {
+                    Some(id) => id == uid.id,
+                    None => uid.allow_proxy,
+                };
+                if ok {
+                    Ok(None)
+                } else {
+                    Err(ApeError::UserMismatch(ctx.clone()))
+                }
+            }
+        }
+    }
+}
+
+fn grants(
+    map: &HashMap<DieselUlid, PermissionLevel>,
+    to_grant: fn((DieselUlid, PermissionLevel)) -> ResWithPerm,
+) -> impl Iterator<Item = ResWithPerm> + '_ {
+    map.iter().map(move |(id, lvl)| to_grant((*id, *lvl)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aruna_rust_api::api::storage::models::v2::DataClass;
+
+    #[test]
+    fn test_permission_index() {
+        let project = DieselUlid::generate();
+        let dataset = DieselUlid::generate();
+        let object = DieselUlid::generate();
+        let index = PermissionIndex::from(&AllUserPermission {
+            perms: vec![
+                ResWithPerm::Project((project, PermissionLevel::Read)),
+                ResWithPerm::Project((project, PermissionLevel::Write)),
+                ResWithPerm::Dataset((dataset, PermissionLevel::Read)),
+                ResWithPerm::Object((object, PermissionLevel::Append)),
+            ],
+            user_id: Some(DieselUlid::generate()),
+            ..Default::default()
+        });
+
+        // Duplicate grants keep the highest level
+        assert_eq!(index.len(), 3);
+        assert_eq!(
+            index.get(&Resource::Project(project)),
+            Some(ResWithPerm::Project((project, PermissionLevel::Write)))
+        );
+        assert_eq!(index.get(&Resource::Collection(project)), None);
+
+        // Direct grants are resolved without the hierarchy
+        let ctx = Context::res_obj(object, PermissionLevels::APPEND, false);
+        assert!(matches!(index.check_ctx(&ctx), Ok(None)));
+
+        // Only sufficient higher grants can be inherited
+        let ctx = Context::res_obj(object, PermissionLevels::WRITE, false);
+        let (res, perm) = index.check_ctx(&ctx).unwrap().unwrap();
+        assert_eq!(res, Resource::Object(object));
+        assert!(index.grants_inherited(&Resource::Project(project), &perm));
+        assert!(!index.grants_inherited(&Resource::Dataset(dataset), &perm));
+        assert!(matches!(
+            index.check_ctx(&Context::res_proj(Some((
+                DieselUlid::generate(),
+                PermissionLevels::READ,
+                false
+            )))),
+            Err(ApeError::InsufficientLevel(_))
+        ));
+
+        // Confidential resources only inherit dataset grants
+        let confidential = Context::ResourceContext(ResourceContext::Object(
+            ApeResourcePermission::new(DieselUlid::generate(), PermissionLevels::WRITE, false)
+                .with_data_class(DataClass::Confidential),
+        ));
+        assert!(matches!(
+            index.check_ctx(&confidential),
+            Err(ApeError::InsufficientLevel(_))
+        ));
+
+        // Only personal tokens use the service account and admin rights
+        let rights = |scope| {
+            PermissionIndex::from(&AllUserPermission {
+                is_sa: true,
+                is_admin: true,
+                scope,
+                ..Default::default()
+            })
+        };
+        let sa_ctx = Context::res_obj(object, PermissionLevels::WRITE, true);
+        let personal = rights(TokenScope::Personal);
+        assert!(matches!(personal.check_ctx(&sa_ctx), Ok(None)));
+        assert!(matches!(personal.check_ctx(&Context::admin()), Ok(None)));
+        for scope in [TokenScope::Scoped, TokenScope::Delegated] {
+            let scoped = rights(scope);
+            assert!(scoped.check_ctx(&sa_ctx).is_err());
+            assert!(scoped.check_ctx(&Context::admin()).is_err());
+        }
+    }
+}
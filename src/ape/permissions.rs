@@ -1,41 +1,140 @@
-use super::structs::AllUserPermission;
-use anyhow::anyhow;
-use anyhow::Result;
-use aruna_rust_api::api::storage::models::v2::User;
+use super::structs::{AllUserPermission, ResWithPerm, TokenScope};
+use crate::error::ApeError;
+use aruna_rust_api::api::storage::models::v2::{Permission, User};
 use diesel_ulid::DieselUlid;
 use std::str::FromStr;
 
 pub trait GetPermissions {
-    fn get_permissions(&self, token_id: Option<DieselUlid>) -> Result<AllUserPermission>;
+    fn get_permissions(&self, token_id: Option<DieselUlid>) -> Result<AllUserPermission, ApeError>;
 }
 
 impl GetPermissions for User {
-    fn get_permissions(&self, token_id: Option<DieselUlid>) -> Result<AllUserPermission> {
+    fn get_permissions(&self, token_id: Option<DieselUlid>) -> Result<AllUserPermission, ApeError> {
         let attributes = self
             .attributes
             .clone()
-            .ok_or_else(|| anyhow!("Missing user attributes"))?;
+            .ok_or_else(|| ApeError::Internal("Missing user attributes".to_string()))?;
 
         let mut all_user_perm = AllUserPermission {
             perms: vec![],
-            user_id: Some(DieselUlid::from_str(self.id.as_str())?),
+            user_id: Some(
+                DieselUlid::from_str(self.id.as_str())
+                    .map_err(|e| ApeError::Internal(e.to_string()))?,
+            ),
             is_sa: attributes.service_account,
             is_admin: attributes.global_admin,
+            scope: TokenScope::Personal,
         };
 
+        // Tokens without an attached permission are personal tokens,
+        // all others are scoped to their own permissions only. The proto has no
+        // explicit personal flag and only a single permission per token.
         if let Some(t_id) = token_id {
-            for t in attributes.tokens {
-                if t.id == t_id.to_string() {
-                    if let Some(perm) = t.permission {
-                        all_user_perm.perms.push(perm.try_into()?);
-                        return Ok(all_user_perm);
-                    }
-                }
+            let token = attributes
+                .tokens
+                .into_iter()
+                .find(|t| t.id == t_id.to_string())
+                .ok_or(ApeError::TokenRevoked(t_id))?;
+            if let Some(perm) = token.permission {
+                all_user_perm.scope = TokenScope::Scoped;
+                all_user_perm.is_admin = false;
+                all_user_perm.is_sa = false;
+                all_user_perm.perms.push(to_res_with_perm(perm)?);
+                return Ok(all_user_perm);
             }
         }
         for perm in attributes.personal_permissions {
-            all_user_perm.perms.push(perm.try_into()?);
+            all_user_perm.perms.push(to_res_with_perm(perm)?);
         }
         Ok(all_user_perm)
     }
 }
+
+fn to_res_with_perm(perm: Permission) -> Result<ResWithPerm, ApeError> {
+    perm.try_into()
+        .map_err(|e: anyhow::Error| ApeError::Internal(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aruna_rust_api::api::storage::models::v2::{
+        permission::ResourceId, PermissionLevel, Token, UserAttributes,
+    };
+
+    fn generate_user(token_id: DieselUlid, token_perm: Option<Permission>) -> User {
+        let user_id = DieselUlid::generate();
+        User {
+            id: user_id.to_string(),
+            attributes: Some(UserAttributes {
+                global_admin: true,
+                service_account: true,
+                tokens: vec![Token {
+                    id: token_id.to_string(),
+                    user_id: user_id.to_string(),
+                    permission: token_perm,
+                    ..Default::default()
+                }],
+                personal_permissions: vec![
+                    Permission {
+                        permission_level: PermissionLevel::Admin as i32,
+                        resource_id: Some(ResourceId::ProjectId(
+                            DieselUlid::generate().to_string(),
+                        )),
+                    },
+                    Permission {
+                        permission_level: PermissionLevel::Write as i32,
+                        resource_id: Some(ResourceId::ProjectId(
+                            DieselUlid::generate().to_string(),
+                        )),
+                    },
+                ],
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_unknown_token_is_rejected() {
+        let token_id = DieselUlid::generate();
+        let user = generate_user(token_id, None);
+
+        assert_eq!(user.get_permissions(None).unwrap().perms.len(), 2);
+        assert_eq!(user.get_permissions(Some(token_id)).unwrap().perms.len(), 2);
+
+        let unknown = DieselUlid::generate();
+        assert!(matches!(
+            user.get_permissions(Some(unknown)),
+            Err(ApeError::TokenRevoked(id)) if id == unknown
+        ));
+    }
+
+    #[test]
+    fn test_scoped_and_personal_tokens() {
+        let token_id = DieselUlid::generate();
+        let personal = generate_user(token_id, None)
+            .get_permissions(Some(token_id))
+            .unwrap();
+        assert_eq!(personal.scope, TokenScope::Personal);
+        assert!(personal.is_admin && personal.is_sa);
+        assert_eq!(personal.perms.len(), 2);
+
+        let dataset_id = DieselUlid::generate();
+        let scoped = generate_user(
+            token_id,
+            Some(Permission {
+                permission_level: PermissionLevel::Read as i32,
+                resource_id: Some(ResourceId::DatasetId(dataset_id.to_string())),
+            }),
+        )
+        .get_permissions(Some(token_id))
+        .unwrap();
+        assert_eq!(scoped.scope, TokenScope::Scoped);
+        assert!(!scoped.is_admin && !scoped.is_sa);
+        assert_eq!(
+            scoped.perms,
+            vec![ResWithPerm::Dataset((dataset_id, PermissionLevel::Read))]
+        );
+    }
+}
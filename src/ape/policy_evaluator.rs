@@ -1,16 +1,46 @@
 use super::{
-    permissions::GetPermissions,
-    structs::{AllUserPermission, Context},
+    decision::{BatchDecision, Decision},
+    decision_cache::{user_fingerprint, DecisionCache, DecisionCacheStats, Snapshot},
+    hierarchy::{Hierarchy, ParentIndex},
+    permission_cache::PermissionCache,
+    permission_index::PermissionIndex,
+    structs::{AllUserPermission, Context, PermissionLevels, ResWithPerm, TokenScope},
 };
-use crate::token::token_handler::TokenHandler;
-use anyhow::{anyhow, Result};
+use crate::error::ApeError;
+use crate::token::issuer::IssuerConfig;
+use crate::token::oidc::RefreshStatus;
+use crate::token::token_handler::{peek_expiry, TokenHandler, TokenInfo};
+use crate::token::validation::ClaimValidation;
+use anyhow::Result;
+use aruna_cache::cache::Cache;
 use aruna_cache::notifications::NotificationCache;
+use aruna_cache::structs::Resource;
+use aruna_rust_api::api::storage::models::v2::{generic_resource, DataClass, User};
 use diesel_ulid::DieselUlid;
-use std::{collections::HashSet, sync::Arc};
+use std::str::FromStr;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, RwLock, RwLockWriteGuard},
+    time::Duration,
+};
+
+/// Default maximum age of the parent index before it is rebuilt
+const HIERARCHY_TTL: Duration = Duration::from_secs(60);
+/// Default number of cached permission indices
+const PERMISSION_CACHE_CAPACITY: usize = 10_000;
+/// Default maximum age of a cached permission index
+const PERMISSION_CACHE_TTL: Duration = Duration::from_secs(300);
 
 pub struct PolicyEvaluator {
     cache: Arc<NotificationCache>,
     token_handler: TokenHandler,
+    /// Permission indices by user and token
+    permission_cache: PermissionCache,
+    /// Optional cache of granted decisions
+    decision_cache: Option<DecisionCache>,
+    /// Parents of all resources, shared by all evaluations
+    parent_index: RwLock<Option<Arc<ParentIndex>>>,
+    hierarchy_ttl: Duration,
 }
 
 impl PolicyEvaluator {
@@ -18,87 +48,886 @@ impl PolicyEvaluator {
         Ok(PolicyEvaluator {
             cache: cache.clone(),
             token_handler: TokenHandler::new(cache.clone(), oidc_realminfo.to_string()),
+            permission_cache: PermissionCache::new(PERMISSION_CACHE_CAPACITY, PERMISSION_CACHE_TTL),
+            decision_cache: None,
+            parent_index: RwLock::default(),
+            hierarchy_ttl: HIERARCHY_TTL,
         })
     }
 
+    pub async fn with_issuers(
+        issuers: Vec<IssuerConfig>,
+        aruna_validation: ClaimValidation,
+        cache: Arc<NotificationCache>,
+    ) -> Result<Self> {
+        Ok(PolicyEvaluator {
+            cache: cache.clone(),
+            token_handler: TokenHandler::with_issuers(cache.clone(), issuers, aruna_validation),
+            permission_cache: PermissionCache::new(PERMISSION_CACHE_CAPACITY, PERMISSION_CACHE_TTL),
+            decision_cache: None,
+            parent_index: RwLock::default(),
+            hierarchy_ttl: HIERARCHY_TTL,
+        })
+    }
+
+    /// Caches up to `capacity` granted decisions of `check_context` for at most `ttl`
+    pub fn with_decision_cache(mut self, capacity: usize, ttl: Duration) -> Self {
+        self.decision_cache = Some(DecisionCache::new(capacity, ttl));
+        self
+    }
+
+    /// Keeps up to `capacity` permission indices for at most `ttl`
+    pub fn with_permission_cache(mut self, capacity: usize, ttl: Duration) -> Self {
+        self.permission_cache = PermissionCache::new(capacity, ttl);
+        self
+    }
+
+    /// Rebuilds the parent index at least every `ttl`, relations added to the cache
+    /// without `resource_update` or `invalidate_hierarchy` are missing until then
+    pub fn with_hierarchy_ttl(mut self, ttl: Duration) -> Self {
+        self.hierarchy_ttl = ttl;
+        self
+    }
+
+    /// Skips the signature verification of up to `capacity` already verified tokens
+    /// for at most `max_age`
+    pub fn with_token_cache(mut self, capacity: usize, max_age: Duration) -> Self {
+        self.token_handler = self.token_handler.with_token_cache(capacity, max_age);
+        self
+    }
+
+    /// Forgets all verified tokens, e.g. after an OIDC provider rotated its keys
+    pub fn clear_token_cache(&self) -> Result<(), ApeError> {
+        self.token_handler.clear_token_cache()
+    }
+
+    /// Rejects the token with this `TokenInfo::revocation_id` immediately,
+    /// revocations are local to this process, see `TokenHandler::revoke_token`
+    pub fn revoke_token(&self, token_id: DieselUlid) -> Result<(), ApeError> {
+        self.token_handler.revoke_token(token_id)
+    }
+
+    pub fn decision_cache_stats(&self) -> Result<Option<DecisionCacheStats>, ApeError> {
+        self.decision_cache
+            .as_ref()
+            .map(|decisions| decisions.stats())
+            .transpose()
+    }
+
+    /// Starts the background refresh of all OIDC provider keys
+    pub fn start_key_refresh(&mut self, ttl: Duration) {
+        self.token_handler.start_key_refresh(ttl)
+    }
+
+    pub fn key_refresh_status(&self) -> HashMap<String, RefreshStatus> {
+        self.token_handler.key_refresh_status()
+    }
+
     pub async fn check_multi_context(
         &self,
         token: &str,
         ctxs: Vec<Context>,
-    ) -> Result<Option<DieselUlid>> {
-        let (user_id, token_id) = self.token_handler.process_token(token).await?;
-
-        let perms = if let Some(uid) = user_id {
-            self.get_user_permissions(uid, token_id)?
-        } else {
-            AllUserPermission::default()
-        };
+    ) -> Result<Option<DieselUlid>, ApeError> {
+        let info = self.token_handler.process_token(token).await?;
+        let hierarchy = self.hierarchy()?;
+        let perms = self.get_token_permissions(&info, &hierarchy)?;
 
-        let mut ress = Vec::new();
-        let mut outer_constraints = HashSet::new();
         for ctx in ctxs {
-            let (ok, rescon) = perms.compare_ctx(ctx);
+            check_hierarchy(&hierarchy, &perms, ctx)?;
+        }
 
-            if !ok {
-                return Err(anyhow!("Invalid permissions"));
-            }
+        Ok(info.user_id)
+    }
 
-            if let Some((res, constraints)) = rescon {
-                ress.push(res);
-                outer_constraints.extend(constraints);
-            }
-        }
+    /// Evaluates every context after a single token verification and permission lookup,
+    /// failing contexts do not affect the others
+    pub async fn check_contexts_batch(
+        &self,
+        token: &str,
+        ctxs: Vec<Context>,
+    ) -> Result<BatchDecision, ApeError> {
+        let (info, hierarchy, perms) = self.prepare_batch(token).await?;
+        Ok(BatchDecision {
+            user_id: info.user_id,
+            results: check_batch(&hierarchy, &perms, ctxs),
+        })
+    }
+
+    /// Returns the resources the caller can access with `level`, in the given order,
+    /// the service account shortcut never applies
+    pub async fn filter_resources(
+        &self,
+        token: &str,
+        level: PermissionLevels,
+        ids: impl IntoIterator<Item = Resource>,
+    ) -> Result<Vec<Resource>, ApeError> {
+        let (_, hierarchy, perms) = self.prepare_batch(token).await?;
+        Ok(filter_granted(&hierarchy, &perms, level, ids))
+    }
 
-        if !outer_constraints.is_empty() {
-            self.cache.cache.check_from_multi_with_targets(
-                ress.iter().collect(),
-                outer_constraints.into_iter().collect(),
-            )?;
+    /// Returns all resources the user can access with at least `min_level`,
+    /// the personal permissions of the user are expanded through the hierarchy
+    pub fn accessible_resources(
+        &self,
+        user_id: DieselUlid,
+        min_level: PermissionLevels,
+    ) -> Result<HashSet<Resource>, ApeError> {
+        let perms = self.get_user_permissions(user_id, None)?;
+        Ok(expand_grants(&self.hierarchy()?, &perms, min_level))
+    }
+
+    /// Verifies the token once for many contexts
+    async fn prepare_batch(
+        &self,
+        token: &str,
+    ) -> Result<(TokenInfo, Hierarchy<'_>, Arc<PermissionIndex>), ApeError> {
+        let info = self.token_handler.process_token(token).await?;
+        let hierarchy = self.hierarchy()?;
+        let perms = self.get_token_permissions(&info, &hierarchy)?;
+        Ok((info, hierarchy, perms))
+    }
+
+    pub async fn check_context(
+        &self,
+        token: &str,
+        ctx: Context,
+    ) -> Result<Option<DieselUlid>, ApeError> {
+        let Some(decisions) = &self.decision_cache else {
+            let info = self.token_handler.process_token(token).await?;
+            let hierarchy = self.hierarchy()?;
+            let perms = self.get_token_permissions(&info, &hierarchy)?;
+            check_hierarchy(&hierarchy, &perms, ctx)?;
+            return Ok(info.user_id);
+        };
+        let hierarchy = self.hierarchy()?;
+        let is_revoked = |id| self.token_handler.is_revoked(id).unwrap_or(true);
+        if let Some(user_id) = decisions.get(&hierarchy, token, &ctx, is_revoked)? {
+            // Removed signing keys must reject cached decisions as well
+            self.token_handler.recheck_aruna_key(token).await?;
+            return Ok(user_id);
         }
 
-        Ok(user_id)
+        let info = self.token_handler.process_token(token).await?;
+        // The snapshot must be taken before the permissions are evaluated,
+        // later updates of the user or the resource invalidate the decision
+        let mut snapshot = Snapshot {
+            user: info
+                .user_id
+                .and_then(|id| user_fingerprint(&self.cache.cache, id)),
+            data_class: ctx
+                .resource()
+                .and_then(|res| get_data_class(&self.cache.cache, &res)),
+            ancestor: None,
+        };
+        let perms = self.get_token_permissions(&info, &hierarchy)?;
+        snapshot.ancestor = check_hierarchy(&hierarchy, &perms, ctx.clone())?;
+        // Embedded permissions are narrowed through the hierarchy,
+        // the snapshot does not cover these
+        if info.embedded_perms.is_none() {
+            decisions.insert(token, ctx, &info, snapshot, peek_expiry(token))?;
+        }
+        Ok(info.user_id)
     }
 
-    pub async fn check_context(&self, token: &str, ctx: Context) -> Result<Option<DieselUlid>> {
-        let (user_id, token_id) = self.token_handler.process_token(token).await?;
+    /// Checks the context for an optional token, anonymous callers
+    /// are only granted READ access to public resources
+    pub async fn check_context_optional(
+        &self,
+        token: Option<&str>,
+        ctx: Context,
+    ) -> Result<Option<DieselUlid>, ApeError> {
+        match token {
+            Some(token) => self.check_context(token, ctx).await,
+            None => {
+                check_anonymous(&self.cache.cache, ctx)?;
+                Ok(None)
+            }
+        }
+    }
 
-        let perms = if let Some(uid) = user_id {
-            self.get_user_permissions(uid, token_id)?
-        } else {
-            AllUserPermission::default()
+    /// Evaluates a context like `check_context` but returns a trace of the decision
+    pub async fn explain_context(&self, token: &str, ctx: Context) -> Decision {
+        let ctx = classify(&self.cache.cache, ctx);
+        let mut decision = Decision::new(ctx.clone());
+        let info = match self.token_handler.process_token(token).await {
+            Ok(info) => info,
+            Err(e) => return decision.deny(e),
         };
+        decision.user_id = info.user_id;
+        decision.token_id = info.token_id;
 
-        let (ok, rescon) = perms.compare_ctx(ctx);
+        let hierarchy = match self.hierarchy() {
+            Ok(hierarchy) => hierarchy,
+            Err(e) => return decision.deny(e),
+        };
+        let perms = match self.get_token_permissions(&info, &hierarchy) {
+            Ok(perms) => perms,
+            Err(e) => return decision.deny(e),
+        };
 
-        if !ok {
-            return Err(anyhow!("Invalid permissions"));
+        if let (Some(res), Some(perm)) = (ctx.resource(), ctx.resource_permission()) {
+            decision.add_check(perms.check_single_perm(&res, perm));
         }
 
-        if let Some((res, constraints)) = rescon {
-            self.cache
-                .cache
-                .check_with_targets(&res, constraints.into_iter().collect())?;
+        match check_hierarchy(&hierarchy, &perms, ctx) {
+            Ok(ancestor) => {
+                decision.inherited_from = ancestor;
+                decision.grant()
+            }
+            Err(e) => decision.deny(e),
         }
+    }
 
-        Ok(user_id)
+    /// Returns the permissions of the token, tokens with embedded permissions
+    /// only keep the embedded permissions the user still has
+    fn get_token_permissions(
+        &self,
+        info: &TokenInfo,
+        hierarchy: &Hierarchy,
+    ) -> Result<Arc<PermissionIndex>, ApeError> {
+        let Some(uid) = info.user_id else {
+            return Ok(Arc::default());
+        };
+        let index = self.get_user_permissions(uid, info.token_id)?;
+        match &info.embedded_perms {
+            Some(delegated) => Ok(Arc::new(narrow_delegation(hierarchy, &index, delegated))),
+            None => Ok(index),
+        }
     }
 
+    /// Returns the indexed permissions of the user for the token,
+    /// indices are rebuilt as soon as the attributes of the cached user change
     fn get_user_permissions(
         &self,
         user: DieselUlid,
         token: Option<DieselUlid>,
-    ) -> Result<AllUserPermission> {
-        let user = self
-            .cache
-            .cache
-            .get_user(user)
-            .ok_or_else(|| anyhow!("User not found"))?;
-        user.get_permissions(token)
+    ) -> Result<Arc<PermissionIndex>, ApeError> {
+        self.permission_cache
+            .get_or_build(&self.cache.cache, user, token)
+    }
+
+    /// Drops all cached permission indices and decisions of the user
+    pub fn invalidate_user(&self, user_id: DieselUlid) -> Result<(), ApeError> {
+        if let Some(decisions) = &self.decision_cache {
+            decisions.remove_user(user_id)?;
+        }
+        self.permission_cache.remove_user(user_id)
+    }
+
+    /// Applies a user update to the cache, refreshes the identities
+    /// and drops the cached permissions of the user
+    ///
+    /// The cache indexes users by their first external id,
+    /// users without external ids are rejected.
+    pub fn user_update(&self, user: User) -> Result<(), ApeError> {
+        let user_id =
+            DieselUlid::from_str(&user.id).map_err(|e| ApeError::Internal(e.to_string()))?;
+        if user.external_ids.is_empty() {
+            return Err(ApeError::InvalidArgument(format!(
+                "User {user_id} has no external ids"
+            )));
+        }
+        self.cache
+            .user_update(user.clone())
+            .map_err(|e| ApeError::Internal(e.to_string()))?;
+        self.token_handler.user_updated(&user)?;
+        self.invalidate_user(user_id)
+    }
+
+    /// Drops all cached permission indices and decisions
+    pub fn invalidate_permissions(&self) -> Result<(), ApeError> {
+        self.invalidate_hierarchy()?;
+        self.permission_cache.clear()
+    }
+
+    /// Drops all cached decisions and the parent index, changes of the hierarchy
+    /// or data classes are already detected when a cached decision is used
+    pub fn invalidate_hierarchy(&self) -> Result<(), ApeError> {
+        if let Some(decisions) = &self.decision_cache {
+            decisions.clear()?;
+        }
+        *self.write_parent_index()? = None;
+        Ok(())
+    }
+
+    /// Applies a resource update to the cache and rebuilds the parent index on next use
+    pub fn resource_update(
+        &self,
+        res: generic_resource::Resource,
+        shared_id: DieselUlid,
+        persistent_resource: Resource,
+    ) -> Result<(), ApeError> {
+        self.cache
+            .resource_update(res, shared_id, persistent_resource);
+        *self.write_parent_index()? = None;
+        Ok(())
+    }
+
+    /// Returns the hierarchy with the shared parent index, the index is built
+    /// on first use and rebuilt once it is older than the hierarchy TTL
+    ///
+    /// Only one caller rebuilds a stale index, all others wait for the write lock
+    /// and use the new index.
+    fn hierarchy(&self) -> Result<Hierarchy<'_>, ApeError> {
+        let current = self
+            .parent_index
+            .read()
+            .map_err(|_| ApeError::Internal("Poisoned parent index".to_string()))?
+            .clone();
+        let index = match current {
+            Some(index) if index.age() < self.hierarchy_ttl => index,
+            _ => {
+                let mut current = self.write_parent_index()?;
+                match current.as_ref() {
+                    Some(index) if index.age() < self.hierarchy_ttl => index.clone(),
+                    _ => current
+                        .insert(Arc::new(ParentIndex::build(&self.cache.cache)))
+                        .clone(),
+                }
+            }
+        };
+        Ok(Hierarchy::indexed(&self.cache.cache, index))
+    }
+
+    fn write_parent_index(
+        &self,
+    ) -> Result<RwLockWriteGuard<'_, Option<Arc<ParentIndex>>>, ApeError> {
+        self.parent_index
+            .write()
+            .map_err(|_| ApeError::Internal("Poisoned parent index".to_string()))
     }
 }
 
+/// Checks the context against the permissions, resources without a direct grant
+/// are granted by their most specific ancestor with a sufficient grant
+///
+/// Returns the granting ancestor if access was inherited
+fn check_hierarchy(
+    hierarchy: &Hierarchy,
+    perms: &PermissionIndex,
+    ctx: Context,
+) -> Result<Option<Resource>, ApeError> {
+    let ctx = classify(hierarchy.cache, ctx);
+    match perms.check_ctx(&ctx)? {
+        None => Ok(None),
+        Some((res, perm)) => hierarchy
+            .find_granting_ancestor(&res, |ancestor| perms.grants_inherited(ancestor, &perm))
+            .map(Some)
+            .ok_or(ApeError::HierarchyConstraintFailed(ctx)),
+    }
+}
+
+/// Checks every context on its own, failed contexts never affect the others
+fn check_batch(
+    hierarchy: &Hierarchy,
+    perms: &PermissionIndex,
+    ctxs: Vec<Context>,
+) -> Vec<Result<(), ApeError>> {
+    ctxs.into_iter()
+        .map(|ctx| check_hierarchy(hierarchy, perms, ctx).map(|_| ()))
+        .collect()
+}
+
+/// Keeps the resources that are granted with `level`
+fn filter_granted(
+    hierarchy: &Hierarchy,
+    perms: &PermissionIndex,
+    level: PermissionLevels,
+    ids: impl IntoIterator<Item = Resource>,
+) -> Vec<Resource> {
+    ids.into_iter()
+        .filter(|res| {
+            let ctx = Context::res(res, level.clone(), false);
+            check_hierarchy(hierarchy, perms, ctx).is_ok()
+        })
+        .collect()
+}
+
+/// Expands all grants with at least `min_level` to the resources below them
+fn expand_grants(
+    hierarchy: &Hierarchy,
+    perms: &PermissionIndex,
+    min_level: PermissionLevels,
+) -> HashSet<Resource> {
+    let mut accessible = HashSet::new();
+    // Confidential resources only inherit dataset grants,
+    // project and collection grants still reach everything below them
+    for inherit_confidential in [true, false] {
+        let mut stack: Vec<Resource> = perms
+            .grants()
+            .filter(|grant| PermissionLevels::from(grant.level()) >= min_level)
+            .filter(|grant| {
+                matches!(grant, ResWithPerm::Dataset(_) | ResWithPerm::Object(_))
+                    == inherit_confidential
+            })
+            .map(|grant| grant.resource())
+            .collect();
+        accessible.extend(stack.iter().cloned());
+        let mut visited = HashSet::new();
+        while let Some(res) = stack.pop() {
+            if !visited.insert(res.clone()) {
+                continue;
+            }
+            for child in hierarchy.get_children(&res) {
+                if inherit_confidential
+                    || get_data_class(hierarchy.cache, &child) != Some(DataClass::Confidential)
+                {
+                    accessible.insert(child.clone());
+                }
+                stack.push(child);
+            }
+        }
+    }
+    accessible
+}
+
+/// Anonymous callers may read public resources, everything else requires a token
+fn check_anonymous(cache: &Cache, ctx: Context) -> Result<(), ApeError> {
+    if ctx == Context::Empty {
+        return Ok(());
+    }
+    let public = match (ctx.resource(), ctx.resource_permission()) {
+        (Some(res), Some(perm)) if perm.level <= PermissionLevels::READ => {
+            get_data_class(cache, &res) == Some(DataClass::Public)
+        }
+        _ => false,
+    };
+    if public {
+        Ok(())
+    } else {
+        Err(ApeError::AuthenticationRequired(ctx))
+    }
+}
+
+/// Sets the data class of the requested resource, the class of the cache takes precedence
+///
+/// Unknown resources keep the class of the caller, unless it is public:
+/// the public shortcut only applies to resources that are public in the cache.
+fn classify(cache: &Cache, mut ctx: Context) -> Context {
+    let data_class = ctx.resource().and_then(|res| get_data_class(cache, &res));
+    if let Some(perm) = ctx.resource_permission_mut() {
+        match data_class {
+            Some(data_class) => perm.data_class = Some(data_class),
+            None if perm.data_class == Some(DataClass::Public) => perm.data_class = None,
+            None => (),
+        }
+    }
+    ctx
+}
+
+/// Returns the data class of a cached resource
+pub(crate) fn get_data_class(cache: &Cache, res: &Resource) -> Option<DataClass> {
+    let data_class = match cache.get_resource(res)? {
+        generic_resource::Resource::Project(p) => p.data_class,
+        generic_resource::Resource::Collection(c) => c.data_class,
+        generic_resource::Resource::Dataset(d) => d.data_class,
+        generic_resource::Resource::Object(o) => o.data_class,
+    };
+    DataClass::from_i32(data_class)
+}
+
+/// Intersects delegated permissions with the permissions of the user,
+/// delegations never inherit the admin or service account shortcuts
+fn narrow_delegation(
+    hierarchy: &Hierarchy,
+    user: &PermissionIndex,
+    delegated: &[ResWithPerm],
+) -> PermissionIndex {
+    let perms = delegated
+        .iter()
+        .filter(|perm| check_hierarchy(hierarchy, user, perm.to_context()).is_ok())
+        .cloned()
+        .collect();
+    PermissionIndex::from(&AllUserPermission {
+        perms,
+        user_id: user.user_id,
+        is_sa: false,
+        is_admin: false,
+        scope: TokenScope::Delegated,
+    })
+}
+
 #[cfg(test)]
 mod tests {
+    use super::*;
+    use crate::ape::hierarchy::tests::relate;
+    use crate::ape::structs::{hierarchy_level, ApeResourcePermission, ResourceContext};
+    use aruna_rust_api::api::storage::models::v2::{Dataset, Object, PermissionLevel};
+    use proptest::collection::vec;
+    use proptest::prelude::*;
+
+    #[test]
+    fn test_check_anonymous() {
+        let cache = Cache::new();
+        let public = DieselUlid::generate();
+        let private = DieselUlid::generate();
+        for (id, data_class) in [(public, DataClass::Public), (private, DataClass::Private)] {
+            cache.object_cache.insert(
+                Resource::Object(id),
+                generic_resource::Resource::Object(Object {
+                    id: id.to_string(),
+                    data_class: data_class as i32,
+                    ..Default::default()
+                }),
+            );
+        }
+
+        assert!(check_anonymous(&cache, Context::empty()).is_ok());
+        assert!(check_anonymous(
+            &cache,
+            Context::res_obj(public, PermissionLevels::READ, true)
+        )
+        .is_ok());
+        let denied = [
+            Context::res_obj(public, PermissionLevels::WRITE, true),
+            Context::res_obj(public, PermissionLevels::APPEND, true),
+            Context::res_obj(private, PermissionLevels::READ, true),
+            Context::res_obj(DieselUlid::generate(), PermissionLevels::READ, true),
+            Context::res_proj(None),
+            Context::admin(),
+        ];
+        for ctx in denied {
+            assert!(matches!(
+                check_anonymous(&cache, ctx),
+                Err(ApeError::AuthenticationRequired(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_classify() {
+        let cache = Cache::new();
+        let id = DieselUlid::generate();
+        cache.object_cache.insert(
+            Resource::Object(id),
+            generic_resource::Resource::Object(Object {
+                id: id.to_string(),
+                data_class: DataClass::Confidential as i32,
+                ..Default::default()
+            }),
+        );
+        let ctx = classify(&cache, Context::res_obj(id, PermissionLevels::READ, false));
+        assert_eq!(
+            ctx.resource_permission().unwrap().data_class,
+            Some(DataClass::Confidential)
+        );
+        let unknown = Context::res_obj(DieselUlid::generate(), PermissionLevels::READ, false);
+        assert_eq!(classify(&cache, unknown.clone()), unknown);
+
+        // Callers can not mark unknown resources as public
+        let claimed = |data_class| {
+            Context::ResourceContext(ResourceContext::Object(
+                ApeResourcePermission::new(id, PermissionLevels::READ, false)
+                    .with_data_class(data_class),
+            ))
+        };
+        let unknown_id = DieselUlid::generate();
+        let claimed_unknown = |data_class| {
+            Context::ResourceContext(ResourceContext::Object(
+                ApeResourcePermission::new(unknown_id, PermissionLevels::READ, false)
+                    .with_data_class(data_class),
+            ))
+        };
+        assert_eq!(
+            classify(&cache, claimed(DataClass::Public)),
+            claimed(DataClass::Confidential)
+        );
+        assert_eq!(
+            classify(&cache, claimed_unknown(DataClass::Public)),
+            Context::res_obj(unknown_id, PermissionLevels::READ, false)
+        );
+        assert_eq!(
+            classify(&cache, claimed_unknown(DataClass::Confidential)),
+            claimed_unknown(DataClass::Confidential)
+        );
+        let hierarchy = Hierarchy::new(&cache);
+        assert!(matches!(
+            check_hierarchy(
+                &hierarchy,
+                &PermissionIndex::default(),
+                claimed_unknown(DataClass::Public)
+            ),
+            Err(ApeError::InsufficientLevel(_))
+        ));
+    }
+
+    /// Builds a random hierarchy from (level, parent seed) pairs below two projects,
+    /// returns all resources and the parent of each child
+    fn build_hierarchy(
+        nodes: &[(u8, usize)],
+    ) -> (Cache, Vec<Resource>, HashMap<Resource, Resource>) {
+        let cache = Cache::new();
+        let mut resources = vec![
+            Resource::Project(DieselUlid::generate()),
+            Resource::Project(DieselUlid::generate()),
+        ];
+        let mut parents = HashMap::new();
+        for (level, seed) in nodes {
+            let id = DieselUlid::generate();
+            let (child, max_parent_level) = match level {
+                1 => (Resource::Collection(id), 0),
+                2 => (Resource::Dataset(id), 1),
+                _ => (Resource::Object(id), 2),
+            };
+            let candidates: Vec<_> = resources
+                .iter()
+                .filter(|res| hierarchy_level(res) <= max_parent_level)
+                .cloned()
+                .collect();
+            let parent = candidates[seed % candidates.len()].clone();
+            relate(&cache, &parent, &child);
+            parents.insert(child.clone(), parent);
+            resources.push(child);
+        }
+        (cache, resources, parents)
+    }
+
+    fn is_ancestor(
+        parents: &HashMap<Resource, Resource>,
+        ancestor: &Resource,
+        res: &Resource,
+    ) -> bool {
+        let mut current = parents.get(res);
+        while let Some(parent) = current {
+            if parent == ancestor {
+                return true;
+            }
+            current = parents.get(parent);
+        }
+        false
+    }
+
+    fn grant_for(res: &Resource, level: PermissionLevel) -> ResWithPerm {
+        match res {
+            Resource::Project(id) => ResWithPerm::Project((*id, level)),
+            Resource::Collection(id) => ResWithPerm::Collection((*id, level)),
+            Resource::Dataset(id) => ResWithPerm::Dataset((*id, level)),
+            Resource::Object(id) => ResWithPerm::Object((*id, level)),
+        }
+    }
+
+    proptest! {
+        /// Grants flow downward to all descendants, never upward or sideways
+        #[test]
+        fn prop_grants_flow_downward(
+            nodes in vec((1u8..=3, any::<usize>()), 0..24),
+            grant_seed in any::<usize>(),
+            target_seed in any::<usize>(),
+            sufficient in any::<bool>(),
+        ) {
+            let (cache, resources, parents) = build_hierarchy(&nodes);
+            let granted = &resources[grant_seed % resources.len()];
+            let target = &resources[target_seed % resources.len()];
+            let level = if sufficient { PermissionLevel::Write } else { PermissionLevel::Read };
+            let perms = AllUserPermission {
+                perms: vec![grant_for(granted, level)],
+                user_id: Some(DieselUlid::generate()),
+                ..Default::default()
+            };
+
+            let perms = PermissionIndex::from(&perms);
+            let ctx = Context::res(target, PermissionLevels::WRITE, false);
+            let result = check_hierarchy(&Hierarchy::new(&cache), &perms, ctx);
+            let expected = sufficient && (granted == target || is_ancestor(&parents, granted, target));
+            prop_assert_eq!(result.is_ok(), expected);
+        }
+    }
+
+    #[test]
+    fn test_narrow_delegation() {
+        let cache = Cache::new();
+        let project = DieselUlid::generate();
+        let collection = DieselUlid::generate();
+        relate(
+            &cache,
+            &Resource::Project(project),
+            &Resource::Collection(collection),
+        );
+
+        let user = AllUserPermission {
+            perms: vec![ResWithPerm::Project((project, PermissionLevel::Write))],
+            user_id: Some(DieselUlid::generate()),
+            is_sa: true,
+            is_admin: true,
+            scope: TokenScope::Personal,
+        };
+        let delegated = vec![
+            ResWithPerm::Collection((collection, PermissionLevel::Read)),
+            ResWithPerm::Collection((DieselUlid::generate(), PermissionLevel::Read)),
+            ResWithPerm::Project((project, PermissionLevel::Admin)),
+        ];
+        let narrowed = narrow_delegation(
+            &Hierarchy::new(&cache),
+            &PermissionIndex::from(&user),
+            &delegated,
+        );
+        assert_eq!(narrowed.len(), 1);
+        assert_eq!(
+            narrowed.get(&Resource::Collection(collection)),
+            Some(delegated[0].clone())
+        );
+        assert_eq!(narrowed.scope, TokenScope::Delegated);
+        assert!(!narrowed.is_admin && !narrowed.is_sa);
+    }
+
+    #[test]
+    fn test_check_batch() {
+        let cache = Cache::new();
+        let project = Resource::Project(DieselUlid::generate());
+        let object = Resource::Object(DieselUlid::generate());
+        let other = Resource::Object(DieselUlid::generate());
+        relate(&cache, &project, &object);
+        let perms = PermissionIndex::from(&AllUserPermission {
+            perms: vec![grant_for(&project, PermissionLevel::Read)],
+            user_id: Some(DieselUlid::generate()),
+            ..Default::default()
+        });
+        let hierarchy = Hierarchy::new(&cache);
+        let user_id = Some(DieselUlid::generate());
+
+        let batch = BatchDecision {
+            user_id,
+            results: check_batch(
+                &hierarchy,
+                &perms,
+                vec![
+                    Context::res(&object, PermissionLevels::READ, false),
+                    Context::res(&object, PermissionLevels::WRITE, false),
+                    Context::res(&other, PermissionLevels::READ, false),
+                    Context::res(&project, PermissionLevels::READ, false),
+                ],
+            ),
+        };
+        // Denied contexts keep their own error and never affect the others
+        assert_eq!(batch.granted(), vec![true, false, false, true]);
+        assert!(!batch.all_granted());
+        assert!(batch.results[1]
+            .as_ref()
+            .is_err_and(|e| e.context().is_some()));
+        assert!(batch.results[2]
+            .as_ref()
+            .is_err_and(|e| e.context().is_some()));
+
+        let batch = BatchDecision {
+            user_id,
+            results: check_batch(
+                &hierarchy,
+                &perms,
+                vec![Context::res(&object, PermissionLevels::READ, false)],
+            ),
+        };
+        assert!(batch.all_granted());
+        assert!(BatchDecision {
+            user_id,
+            results: vec![],
+        }
+        .all_granted());
+    }
+
+    #[test]
+    fn test_filter_granted() {
+        let cache = Cache::new();
+        let project = Resource::Project(DieselUlid::generate());
+        let collection = Resource::Collection(DieselUlid::generate());
+        let dataset = Resource::Dataset(DieselUlid::generate());
+        let objects = [
+            Resource::Object(DieselUlid::generate()),
+            Resource::Object(DieselUlid::generate()),
+        ];
+        let other = Resource::Object(DieselUlid::generate());
+        relate(&cache, &project, &collection);
+        relate(&cache, &collection, &dataset);
+        for object in &objects {
+            relate(&cache, &dataset, object);
+        }
+        let perms = PermissionIndex::from(&AllUserPermission {
+            perms: vec![
+                grant_for(&collection, PermissionLevel::Write),
+                grant_for(&other, PermissionLevel::Read),
+            ],
+            user_id: Some(DieselUlid::generate()),
+            ..Default::default()
+        });
+        let hierarchy = Hierarchy::new(&cache);
+        let ids = vec![
+            objects[1].clone(),
+            other.clone(),
+            project.clone(),
+            objects[0].clone(),
+            dataset.clone(),
+            collection.clone(),
+        ];
+
+        // The order of the requested resources is kept
+        assert_eq!(
+            filter_granted(&hierarchy, &perms, PermissionLevels::WRITE, ids.clone()),
+            vec![
+                objects[1].clone(),
+                objects[0].clone(),
+                dataset.clone(),
+                collection.clone()
+            ]
+        );
+        assert_eq!(
+            filter_granted(&hierarchy, &perms, PermissionLevels::READ, ids).len(),
+            5
+        );
+    }
+
+    #[test]
+    fn test_expand_grants() {
+        let cache = Cache::new();
+        let project = DieselUlid::generate();
+        let confidential = DieselUlid::generate();
+        let object = DieselUlid::generate();
+        let granted = DieselUlid::generate();
+        let resources = vec![
+            Resource::Project(project),
+            Resource::Dataset(confidential),
+            Resource::Object(object),
+            Resource::Dataset(granted),
+            Resource::Object(DieselUlid::generate()),
+            Resource::Project(DieselUlid::generate()),
+        ];
+        relate(&cache, &resources[0], &resources[1]);
+        relate(&cache, &resources[1], &resources[2]);
+        relate(&cache, &resources[5], &resources[3]);
+        relate(&cache, &resources[3], &resources[4]);
+        cache.object_cache.insert(
+            Resource::Dataset(confidential),
+            generic_resource::Resource::Dataset(Dataset {
+                id: confidential.to_string(),
+                data_class: DataClass::Confidential as i32,
+                ..Default::default()
+            }),
+        );
+        let perms = PermissionIndex::from(&AllUserPermission {
+            perms: vec![
+                ResWithPerm::Project((project, PermissionLevel::Write)),
+                ResWithPerm::Dataset((granted, PermissionLevel::Read)),
+            ],
+            user_id: Some(DieselUlid::generate()),
+            ..Default::default()
+        });
+        let hierarchy = Hierarchy::new(&cache);
+
+        let accessible = expand_grants(&hierarchy, &perms, PermissionLevels::WRITE);
+        assert_eq!(
+            accessible,
+            HashSet::from([Resource::Project(project), Resource::Object(object)])
+        );
+        // The expansion matches the evaluation of every single resource
+        for level in [PermissionLevels::READ, PermissionLevels::WRITE] {
+            let accessible = expand_grants(&hierarchy, &perms, level.clone());
+            for res in &resources {
+                let ctx = Context::res(res, level.clone(), false);
+                assert_eq!(
+                    accessible.contains(res),
+                    check_hierarchy(&hierarchy, &perms, ctx).is_ok()
+                );
+            }
+        }
+    }
 
     #[test]
     fn test_filter_perms() {}
@@ -1,15 +1,18 @@
 use std::collections::HashSet;
 use std::str::FromStr;
 
+use super::permission_index::PermissionIndex;
+use crate::error::ApeError;
 use anyhow::anyhow;
 use aruna_cache::structs::Resource;
+use aruna_rust_api::api::storage::models::v2::DataClass;
 use aruna_rust_api::api::storage::models::v2::Permission;
 use aruna_rust_api::api::storage::models::v2::PermissionLevel;
 use diesel_ulid::DieselUlid;
 use serde::Deserialize;
 use serde::Serialize;
 
-#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, PartialOrd, Ord, Clone)]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Clone)]
 pub enum PermissionLevels {
     DENY,
     NONE,
@@ -19,11 +22,14 @@ pub enum PermissionLevels {
     ADMIN,
 }
 
-#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, PartialOrd, Ord, Clone)]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Clone)]
 pub struct ApeResourcePermission {
     pub id: DieselUlid,
     pub level: PermissionLevels,
     pub allow_sa: bool,
+    /// Data class of the resource, looked up from the cache if not set
+    #[serde(default)]
+    pub data_class: Option<DataClass>,
 }
 
 impl ApeResourcePermission {
@@ -32,6 +38,30 @@ impl ApeResourcePermission {
             id,
             level,
             allow_sa,
+            data_class: None,
+        }
+    }
+
+    pub fn with_data_class(mut self, data_class: DataClass) -> Self {
+        self.data_class = Some(data_class);
+        self
+    }
+
+    /// READ is enough for public resources, the evaluator only keeps
+    /// the public class of resources that are public in the cache
+    pub(crate) fn is_public_read(&self) -> bool {
+        self.data_class == Some(DataClass::Public) && self.level <= PermissionLevels::READ
+    }
+
+    /// Confidential resources require an explicit grant on the resource itself
+    /// or on the dataset level, inherited project and collection grants are ignored
+    pub(crate) fn accepts_grant(&self, grant: &ResWithPerm) -> bool {
+        match self.data_class {
+            Some(DataClass::Confidential) => match grant {
+                ResWithPerm::Dataset(_) | ResWithPerm::Object(_) => true,
+                ResWithPerm::Project((id, _)) | ResWithPerm::Collection((id, _)) => id == &self.id,
+            },
+            _ => true,
         }
     }
 }
@@ -49,13 +79,13 @@ impl From<PermissionLevel> for PermissionLevels {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, PartialOrd, Ord, Clone)]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Clone)]
 pub struct ApeUserPermission {
     pub id: DieselUlid,
     pub allow_proxy: bool,
 }
 
-#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, PartialOrd, Ord, Clone)]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Clone)]
 pub enum ResourceContext {
     Project(Option<ApeResourcePermission>),
     Collection(ApeResourcePermission),
@@ -63,7 +93,7 @@ pub enum ResourceContext {
     Object(ApeResourcePermission),
 }
 
-#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, PartialOrd, Ord, Clone)]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Clone)]
 pub enum Context {
     Empty,
     ResourceContext(ResourceContext),
@@ -100,6 +130,16 @@ impl Context {
         )))
     }
 
+    /// Requests `level` on any resource
+    pub fn res(res: &Resource, level: PermissionLevels, allow_sa: bool) -> Self {
+        match res {
+            Resource::Project(id) => Context::res_proj(Some((*id, level, allow_sa))),
+            Resource::Collection(id) => Context::res_col(*id, level, allow_sa),
+            Resource::Dataset(id) => Context::res_ds(*id, level, allow_sa),
+            Resource::Object(id) => Context::res_obj(*id, level, allow_sa),
+        }
+    }
+
     pub fn user(id: DieselUlid, allow_proxy: bool) -> Self {
         Context::User(ApeUserPermission { id, allow_proxy })
     }
@@ -107,6 +147,46 @@ impl Context {
     pub fn admin() -> Self {
         Context::GlobalAdmin
     }
+
+    /// Returns the requested resource for resource contexts
+    pub fn resource(&self) -> Option<Resource> {
+        match self {
+            Context::ResourceContext(ResourceContext::Project(perm)) => {
+                perm.as_ref().map(|p| Resource::Project(p.id))
+            }
+            Context::ResourceContext(ResourceContext::Collection(perm)) => {
+                Some(Resource::Collection(perm.id))
+            }
+            Context::ResourceContext(ResourceContext::Dataset(perm)) => {
+                Some(Resource::Dataset(perm.id))
+            }
+            Context::ResourceContext(ResourceContext::Object(perm)) => {
+                Some(Resource::Object(perm.id))
+            }
+            _ => None,
+        }
+    }
+
+    /// Returns the requested resource permission for resource contexts
+    pub fn resource_permission(&self) -> Option<&ApeResourcePermission> {
+        match self {
+            Context::ResourceContext(ResourceContext::Project(perm)) => perm.as_ref(),
+            Context::ResourceContext(ResourceContext::Collection(perm))
+            | Context::ResourceContext(ResourceContext::Dataset(perm))
+            | Context::ResourceContext(ResourceContext::Object(perm)) => Some(perm),
+            _ => None,
+        }
+    }
+
+    pub fn resource_permission_mut(&mut self) -> Option<&mut ApeResourcePermission> {
+        match self {
+            Context::ResourceContext(ResourceContext::Project(perm)) => perm.as_mut(),
+            Context::ResourceContext(ResourceContext::Collection(perm))
+            | Context::ResourceContext(ResourceContext::Dataset(perm))
+            | Context::ResourceContext(ResourceContext::Object(perm)) => Some(perm),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, PartialEq, Eq, PartialOrd, Ord, Clone)]
@@ -147,116 +227,160 @@ impl TryFrom<Permission> for ResWithPerm {
     }
 }
 
+impl ResWithPerm {
+    pub fn resource(&self) -> Resource {
+        match self {
+            ResWithPerm::Project((id, _)) => Resource::Project(*id),
+            ResWithPerm::Collection((id, _)) => Resource::Collection(*id),
+            ResWithPerm::Dataset((id, _)) => Resource::Dataset(*id),
+            ResWithPerm::Object((id, _)) => Resource::Object(*id),
+        }
+    }
+
+    pub fn level(&self) -> PermissionLevel {
+        match self {
+            ResWithPerm::Project((_, lvl))
+            | ResWithPerm::Collection((_, lvl))
+            | ResWithPerm::Dataset((_, lvl))
+            | ResWithPerm::Object((_, lvl)) => *lvl,
+        }
+    }
+
+    /// Context that requests exactly this permission
+    pub fn to_context(&self) -> Context {
+        Context::res(
+            &self.resource(),
+            PermissionLevels::from(self.level()),
+            false,
+        )
+    }
+}
+
+/// Position of a resource in the hierarchy, projects are at the top
+pub(crate) fn hierarchy_level(res: &Resource) -> u8 {
+    match res {
+        Resource::Project(_) => 0,
+        Resource::Collection(_) => 1,
+        Resource::Dataset(_) => 2,
+        Resource::Object(_) => 3,
+    }
+}
+
+/// Outcome of a single resource permission check against all grants of a user
+#[derive(Debug, PartialEq, Eq, Clone, Default)]
+pub struct PermCheck {
+    /// The service account shortcut granted access
+    pub sa_shortcut: bool,
+    /// Access was granted because READ is enough for public resources
+    pub public_shortcut: bool,
+    /// The grant that directly matched the requested resource
+    pub granted_by: Option<ResWithPerm>,
+    /// Grants for the requested resource with an insufficient level
+    pub denied_by: Vec<ResWithPerm>,
+    /// Sufficient grants that must be ancestors of the requested resource
+    pub constraints: HashSet<Resource>,
+}
+
+/// Defines which permissions a token carries, only personal tokens
+/// use the service account and global admin rights of the user
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Default)]
+pub enum TokenScope {
+    /// No token or a personal token, inherits all permissions of the user
+    #[default]
+    Personal,
+    /// Token that only ever carries its own permissions,
+    /// never the personal or global admin permissions of the user
+    Scoped,
+    /// Delegation or scoped Aruna token, carries the embedded permissions
+    /// that are still covered by the permissions of the user
+    Delegated,
+}
+
 #[derive(Serialize, Deserialize, Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Default)]
 pub struct AllUserPermission {
     pub perms: Vec<ResWithPerm>,
     pub user_id: Option<DieselUlid>,
     pub is_sa: bool,
     pub is_admin: bool,
+    pub scope: TokenScope,
 }
 
 impl AllUserPermission {
-    fn check_single_perm(&self, perm: ApeResourcePermission) -> (bool, Option<HashSet<Resource>>) {
-        if perm.allow_sa && self.is_sa {
-            return (true, None);
-        }
-        let mut constraints = HashSet::new();
-
-        for x in self.perms.iter() {
-            match x {
-                ResWithPerm::Project((id, lvl)) => {
-                    if PermissionLevels::from(*lvl) >= perm.level {
-                        if id == &perm.id {
-                            return (true, None);
-                        } else {
-                            constraints.insert(Resource::Project(id.clone()));
-                        }
-                    }
-                }
-                ResWithPerm::Collection((id, lvl)) => {
-                    if PermissionLevels::from(*lvl) >= perm.level {
-                        if id == &perm.id {
-                            return (true, None);
-                        } else {
-                            constraints.insert(Resource::Project(id.clone()));
-                        }
-                    }
-                }
-                ResWithPerm::Dataset((id, lvl)) => {
-                    if PermissionLevels::from(*lvl) >= perm.level {
-                        if id == &perm.id {
-                            return (true, None);
-                        } else {
-                            constraints.insert(Resource::Project(id.clone()));
-                        }
-                    }
-                }
-                ResWithPerm::Object((id, lvl)) => {
-                    if PermissionLevels::from(*lvl) >= perm.level {
-                        if id == &perm.id {
-                            return (true, None);
-                        } else {
-                            constraints.insert(Resource::Project(id.clone()));
-                        }
-                    }
-                }
-            }
-        }
-        if constraints.is_empty() {
-            (false, None)
-        } else {
-            (true, Some(constraints))
-        }
+    pub fn compare_ctx(
+        &self,
+        ctx: Context,
+    ) -> Result<Option<(Resource, HashSet<Resource>)>, ApeError> {
+        let index = PermissionIndex::from(self);
+        Ok(index.check_ctx(&ctx)?.map(|(res, perm)| {
+            let constraints = index.check_single_perm(&res, &perm).constraints;
+            (res, constraints)
+        }))
     }
+}
 
-    pub fn compare_ctx(&self, ctx: Context) -> (bool, Option<(Resource, HashSet<Resource>)>) {
-        match ctx {
-            Context::GlobalAdmin => {
-                if self.is_admin {
-                    (true, None)
-                } else {
-                    (false, None)
-                }
-            }
-            Context::Empty => (true, None),
-            Context::ResourceContext(res_ctx) => match res_ctx {
-                ResourceContext::Project(pperm) => {
-                    if let Some(perm) = pperm {
-                        let (ok, constraints) = self.check_single_perm(perm.clone());
-                        return (ok, constraints.map(|x| (Resource::Project(perm.id), x)));
-                    } else {
-                        (true, None)
-                    }
-                }
-                ResourceContext::Collection(cperm) => {
-                    let (ok, constraints) = self.check_single_perm(cperm.clone());
-                    return (ok, constraints.map(|x| (Resource::Collection(cperm.id), x)));
-                }
-                ResourceContext::Dataset(dperm) => {
-                    let (ok, constraints) = self.check_single_perm(dperm.clone());
-                    return (ok, constraints.map(|x| (Resource::Collection(dperm.id), x)));
-                }
-                ResourceContext::Object(operm) => {
-                    let (ok, constraints) = self.check_single_perm(operm.clone());
-                    return (ok, constraints.map(|x| (Resource::Collection(operm.id), x)));
-                }
-            },
-            Context::User(uid) => match self.user_id {
-                Some(id) => {
-                    if id == uid.id {
-                        (true, None)
-                    } else {
-                        (false, None)
-                    }
-                }
-                None => {
-                    if uid.allow_proxy {
-                        (true, None)
-                    } else {
-                        (false, None)
-                    }
-                }
-            },
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user_with(perms: Vec<ResWithPerm>) -> AllUserPermission {
+        AllUserPermission {
+            perms,
+            user_id: Some(DieselUlid::generate()),
+            ..Default::default()
         }
     }
+
+    #[test]
+    fn test_data_class_rules() {
+        let project = DieselUlid::generate();
+        let dataset = DieselUlid::generate();
+        let object = DieselUlid::generate();
+        let ctx = |level, data_class| {
+            Context::ResourceContext(ResourceContext::Object(
+                ApeResourcePermission::new(object, level, false).with_data_class(data_class),
+            ))
+        };
+
+        // READ is enough for public resources, everything else requires a grant
+        let nobody = user_with(vec![]);
+        assert!(matches!(
+            nobody.compare_ctx(ctx(PermissionLevels::READ, DataClass::Public)),
+            Ok(None)
+        ));
+        assert!(matches!(
+            nobody.compare_ctx(ctx(PermissionLevels::WRITE, DataClass::Public)),
+            Err(ApeError::InsufficientLevel(_))
+        ));
+        assert!(matches!(
+            nobody.compare_ctx(ctx(PermissionLevels::READ, DataClass::Private)),
+            Err(ApeError::InsufficientLevel(_))
+        ));
+
+        // Inherited project grants are ignored for confidential resources
+        let project_admin = user_with(vec![ResWithPerm::Project((
+            project,
+            PermissionLevel::Admin,
+        ))]);
+        assert!(project_admin
+            .compare_ctx(ctx(PermissionLevels::READ, DataClass::Private))
+            .unwrap()
+            .is_some());
+        assert!(matches!(
+            project_admin.compare_ctx(ctx(PermissionLevels::READ, DataClass::Confidential)),
+            Err(ApeError::InsufficientLevel(_))
+        ));
+
+        // Dataset grants and direct grants are accepted
+        let dataset_reader =
+            user_with(vec![ResWithPerm::Dataset((dataset, PermissionLevel::Read))]);
+        assert!(dataset_reader
+            .compare_ctx(ctx(PermissionLevels::READ, DataClass::Confidential))
+            .unwrap()
+            .is_some());
+        let object_reader = user_with(vec![ResWithPerm::Object((object, PermissionLevel::Read))]);
+        assert!(matches!(
+            object_reader.compare_ctx(ctx(PermissionLevels::READ, DataClass::Confidential)),
+            Ok(None)
+        ));
+    }
 }
@@ -0,0 +1,147 @@
+use crate::ape::structs::Context;
+use diesel_ulid::DieselUlid;
+use jsonwebtoken::errors::ErrorKind;
+use thiserror::Error;
+
+/// All errors that can occur while processing tokens or evaluating a context
+///
+/// Token related variants map to `Unauthenticated`, permission related
+/// variants carry the offending `Context` and map to `PermissionDenied`.
+#[derive(Error, Debug)]
+pub enum ApeError {
+    #[error("Malformed token: {0}")]
+    TokenMalformed(String),
+    #[error("Unknown issuer: {0}")]
+    UnknownIssuer(String),
+    #[error("Unknown signing key: {0}")]
+    UnknownKey(String),
+    #[error("Invalid token signature")]
+    SignatureInvalid,
+    #[error("Token expired")]
+    TokenExpired,
+    #[error("Invalid token issuer")]
+    InvalidIssuer,
+    #[error("Invalid token audience")]
+    InvalidAudience,
+    #[error("Token is not valid yet")]
+    TokenNotYetValid,
+    #[error("Token was issued in the future")]
+    IssuedInFuture,
+    #[error("Token was revoked: {0}")]
+    TokenRevoked(DieselUlid),
+    #[error("Stored token expired: {0}")]
+    StoredTokenExpired(DieselUlid),
+    #[error("User not found: {0}")]
+    UserNotFound(DieselUlid),
+    #[error("No user registered for subject {1} of issuer {0}")]
+    UnregisteredUser(String, String),
+    #[error("Authentication required for: {0:?}")]
+    AuthenticationRequired(Context),
+    #[error("Insufficient permission level for: {0:?}")]
+    InsufficientLevel(Context),
+    #[error("Service accounts are not allowed for: {0:?}")]
+    ServiceAccountNotAllowed(Context),
+    #[error("Hierarchy constraints failed for: {0:?}")]
+    HierarchyConstraintFailed(Context),
+    #[error("Global admin permissions required for: {0:?}")]
+    NotAdmin(Context),
+    #[error("User does not match: {0:?}")]
+    UserMismatch(Context),
+    #[error("Invalid argument: {0}")]
+    InvalidArgument(String),
+    #[error("Internal error: {0}")]
+    Internal(String),
+}
+
+impl ApeError {
+    /// Returns the context that caused a permission error (if any)
+    pub fn context(&self) -> Option<&Context> {
+        match self {
+            ApeError::AuthenticationRequired(ctx)
+            | ApeError::InsufficientLevel(ctx)
+            | ApeError::ServiceAccountNotAllowed(ctx)
+            | ApeError::HierarchyConstraintFailed(ctx)
+            | ApeError::NotAdmin(ctx)
+            | ApeError::UserMismatch(ctx) => Some(ctx),
+            _ => None,
+        }
+    }
+}
+
+impl From<jsonwebtoken::errors::Error> for ApeError {
+    fn from(value: jsonwebtoken::errors::Error) -> Self {
+        match value.kind() {
+            ErrorKind::ExpiredSignature => ApeError::TokenExpired,
+            ErrorKind::InvalidSignature => ApeError::SignatureInvalid,
+            ErrorKind::InvalidIssuer => ApeError::InvalidIssuer,
+            ErrorKind::InvalidAudience => ApeError::InvalidAudience,
+            ErrorKind::MissingRequiredClaim(claim) if claim == "aud" => ApeError::InvalidAudience,
+            ErrorKind::ImmatureSignature => ApeError::TokenNotYetValid,
+            ErrorKind::InvalidEcdsaKey
+            | ErrorKind::InvalidRsaKey(_)
+            | ErrorKind::InvalidKeyFormat
+            | ErrorKind::RsaFailedSigning
+            | ErrorKind::Crypto(_) => ApeError::Internal(value.to_string()),
+            _ => ApeError::TokenMalformed(value.to_string()),
+        }
+    }
+}
+
+impl From<ApeError> for tonic::Status {
+    fn from(value: ApeError) -> Self {
+        match value {
+            ApeError::TokenMalformed(_)
+            | ApeError::UnknownIssuer(_)
+            | ApeError::UnknownKey(_)
+            | ApeError::SignatureInvalid
+            | ApeError::TokenExpired
+            | ApeError::InvalidIssuer
+            | ApeError::InvalidAudience
+            | ApeError::TokenNotYetValid
+            | ApeError::IssuedInFuture
+            | ApeError::TokenRevoked(_)
+            | ApeError::StoredTokenExpired(_)
+            | ApeError::AuthenticationRequired(_) => {
+                tonic::Status::unauthenticated(value.to_string())
+            }
+            ApeError::UserNotFound(_) | ApeError::UnregisteredUser(_, _) => {
+                tonic::Status::not_found(value.to_string())
+            }
+            ApeError::InsufficientLevel(_)
+            | ApeError::ServiceAccountNotAllowed(_)
+            | ApeError::HierarchyConstraintFailed(_)
+            | ApeError::NotAdmin(_)
+            | ApeError::UserMismatch(_) => tonic::Status::permission_denied(value.to_string()),
+            ApeError::InvalidArgument(_) => tonic::Status::invalid_argument(value.to_string()),
+            ApeError::Internal(_) => tonic::Status::internal(value.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tonic::Code;
+
+    #[test]
+    fn test_status_codes() {
+        let ctx = Context::admin();
+        assert_eq!(
+            tonic::Status::from(ApeError::TokenExpired).code(),
+            Code::Unauthenticated
+        );
+        assert_eq!(
+            tonic::Status::from(ApeError::UserNotFound(DieselUlid::generate())).code(),
+            Code::NotFound
+        );
+        assert_eq!(
+            tonic::Status::from(ApeError::NotAdmin(ctx.clone())).code(),
+            Code::PermissionDenied
+        );
+        assert_eq!(
+            tonic::Status::from(ApeError::InvalidArgument("arg".to_string())).code(),
+            Code::InvalidArgument
+        );
+        assert_eq!(ApeError::NotAdmin(ctx.clone()).context(), Some(&ctx));
+    }
+}
@@ -1,2 +1,3 @@
 pub mod ape;
+pub mod error;
 pub mod token;
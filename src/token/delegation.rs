@@ -0,0 +1,60 @@
+use super::minting::ClaimSigner;
+use crate::ape::structs::ResWithPerm;
+use crate::error::ApeError;
+use diesel_ulid::DieselUlid;
+use jsonwebtoken::{get_current_timestamp, Algorithm, EncodingKey};
+use std::time::Duration;
+
+/// Maximum lifetime of a delegation token
+pub const MAX_DELEGATION_LIFETIME: Duration = Duration::from_secs(3600);
+
+/// Mints delegation tokens with the private key of a data proxy
+///
+/// A delegation token is issued on behalf of a user and carries a narrowed
+/// set of permissions, the evaluator only grants permissions the user still has.
+pub struct DelegationSigner {
+    signer: ClaimSigner,
+}
+
+impl DelegationSigner {
+    /// `kid` must reference the data proxy key that is registered for verification
+    pub fn new(kid: impl Into<String>, algorithm: Algorithm, key: EncodingKey) -> Self {
+        DelegationSigner {
+            signer: ClaimSigner::new(kid, algorithm, key),
+        }
+    }
+
+    pub fn from_ed_pem(kid: impl Into<String>, pem: &[u8]) -> Result<Self, ApeError> {
+        Ok(DelegationSigner::new(
+            kid,
+            Algorithm::EdDSA,
+            EncodingKey::from_ed_pem(pem)?,
+        ))
+    }
+
+    /// Sets the `aud` of all delegation tokens, required if the verifier checks audiences
+    pub fn with_audience(mut self, audience: impl Into<String>) -> Self {
+        self.signer.set_audience(audience);
+        self
+    }
+
+    pub fn sign(
+        &self,
+        user_id: DieselUlid,
+        perms: Vec<ResWithPerm>,
+        lifetime: Duration,
+    ) -> Result<String, ApeError> {
+        if lifetime > MAX_DELEGATION_LIFETIME {
+            return Err(ApeError::InvalidArgument(format!(
+                "Delegation lifetime exceeds {}s",
+                MAX_DELEGATION_LIFETIME.as_secs()
+            )));
+        }
+        self.signer.sign(
+            user_id.to_string(),
+            None,
+            get_current_timestamp() + lifetime.as_secs(),
+            Some(perms),
+        )
+    }
+}
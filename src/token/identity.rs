@@ -0,0 +1,269 @@
+use crate::error::ApeError;
+use aruna_cache::cache::Cache;
+use aruna_cache::notifications::NotificationCache;
+use aruna_rust_api::api::storage::models::v2::User;
+use diesel_ulid::DieselUlid;
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::time::{Duration, Instant};
+
+/// Minimum time between two full rebuilds of the identity index
+const IDENTITY_REBUILD_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Maps an identity of an external provider (issuer, subject) to an Aruna user
+pub trait IdentityResolver: Send + Sync {
+    /// Returns `UnregisteredUser` if no user registered this identity
+    fn resolve(&self, issuer: &str, subject: &str) -> Result<DieselUlid, ApeError>;
+
+    /// Called for every user update, resolvers with an index refresh it here
+    fn user_updated(&self, _user: &User) -> Result<(), ApeError> {
+        Ok(())
+    }
+}
+
+/// Resolves identities with the external ids of the users in the notification cache,
+/// the `idp` of an external id must match the configured issuer
+pub struct CacheIdentityResolver {
+    cache: Arc<NotificationCache>,
+    index: IdentityIndex,
+}
+
+impl CacheIdentityResolver {
+    pub fn new(cache: Arc<NotificationCache>) -> Self {
+        CacheIdentityResolver {
+            cache,
+            index: IdentityIndex::new(IDENTITY_REBUILD_INTERVAL),
+        }
+    }
+}
+
+impl IdentityResolver for CacheIdentityResolver {
+    fn resolve(&self, issuer: &str, subject: &str) -> Result<DieselUlid, ApeError> {
+        self.index.resolve(&self.cache.cache, issuer, subject)
+    }
+
+    fn user_updated(&self, user: &User) -> Result<(), ApeError> {
+        self.index.update(user)
+    }
+}
+
+#[derive(Default)]
+struct IdentityEntries {
+    ids: HashMap<(String, String), DieselUlid>,
+    built: Option<Instant>,
+}
+
+/// Index of all external ids (idp, external_id) of the cached users
+///
+/// Entries are refreshed on user updates, every hit is checked against the cached user.
+/// Unknown identities rebuild the index at most once per `rebuild_interval`, in between
+/// the cached users are searched for the missing identity only. This covers users that
+/// were added to the cache without `user_updated`, e.g. by the notification stream.
+struct IdentityIndex {
+    rebuild_interval: Duration,
+    entries: RwLock<IdentityEntries>,
+}
+
+impl IdentityIndex {
+    fn new(rebuild_interval: Duration) -> Self {
+        IdentityIndex {
+            rebuild_interval,
+            entries: RwLock::default(),
+        }
+    }
+
+    fn resolve(&self, cache: &Cache, issuer: &str, subject: &str) -> Result<DieselUlid, ApeError> {
+        let key = (issuer.to_string(), subject.to_string());
+        let has_identity = |user: &User| {
+            user.external_ids
+                .iter()
+                .any(|ext| ext.idp == issuer && ext.external_id == subject)
+        };
+        let registered = |user_id: &DieselUlid| {
+            cache
+                .user_cache
+                .get(user_id)
+                .is_some_and(|user| has_identity(&user))
+        };
+
+        let entries = self.read()?;
+        if let Some(user_id) = entries.ids.get(&key).filter(|id| registered(id)) {
+            return Ok(*user_id);
+        }
+        let outdated = match entries.built {
+            Some(built) => built.elapsed() >= self.rebuild_interval,
+            None => true,
+        };
+        drop(entries);
+        if outdated {
+            let mut entries = self.write()?;
+            entries.ids.clear();
+            for user in cache.user_cache.iter() {
+                for ext in &user.external_ids {
+                    entries.ids.insert(
+                        (ext.idp.to_string(), ext.external_id.to_string()),
+                        *user.key(),
+                    );
+                }
+            }
+            entries.built = Some(Instant::now());
+            if let Some(user_id) = entries.ids.get(&key).filter(|id| registered(id)) {
+                return Ok(*user_id);
+            }
+        } else if let Some(user_id) = cache
+            .user_cache
+            .iter()
+            .find(|user| has_identity(user.value()))
+            .map(|user| *user.key())
+        {
+            self.write()?.ids.insert(key, user_id);
+            return Ok(user_id);
+        }
+        Err(ApeError::UnregisteredUser(
+            issuer.to_string(),
+            subject.to_string(),
+        ))
+    }
+
+    fn update(&self, user: &User) -> Result<(), ApeError> {
+        let user_id =
+            DieselUlid::from_str(&user.id).map_err(|e| ApeError::Internal(e.to_string()))?;
+        let mut entries = self.write()?;
+        entries.ids.retain(|_, id| id != &user_id);
+        for ext in &user.external_ids {
+            entries
+                .ids
+                .insert((ext.idp.to_string(), ext.external_id.to_string()), user_id);
+        }
+        Ok(())
+    }
+
+    fn read(&self) -> Result<RwLockReadGuard<'_, IdentityEntries>, ApeError> {
+        self.entries
+            .read()
+            .map_err(|_| ApeError::Internal("Poisoned identity index".to_string()))
+    }
+
+    fn write(&self) -> Result<RwLockWriteGuard<'_, IdentityEntries>, ApeError> {
+        self.entries
+            .write()
+            .map_err(|_| ApeError::Internal("Poisoned identity index".to_string()))
+    }
+}
+
+/// Static identities that are stored in memory
+#[derive(Default)]
+pub struct MemoryIdentityResolver {
+    ids: RwLock<HashMap<(String, String), DieselUlid>>,
+}
+
+impl MemoryIdentityResolver {
+    pub fn new() -> Self {
+        MemoryIdentityResolver::default()
+    }
+
+    pub fn insert(&self, issuer: &str, subject: &str, user_id: DieselUlid) -> Result<(), ApeError> {
+        self.ids
+            .write()
+            .map_err(|_| ApeError::Internal("Poisoned identity store".to_string()))?
+            .insert((issuer.to_string(), subject.to_string()), user_id);
+        Ok(())
+    }
+}
+
+impl IdentityResolver for MemoryIdentityResolver {
+    fn resolve(&self, issuer: &str, subject: &str) -> Result<DieselUlid, ApeError> {
+        self.ids
+            .read()
+            .map_err(|_| ApeError::Internal("Poisoned identity store".to_string()))?
+            .get(&(issuer.to_string(), subject.to_string()))
+            .copied()
+            .ok_or_else(|| ApeError::UnregisteredUser(issuer.to_string(), subject.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aruna_rust_api::api::storage::models::v2::ExternalId;
+
+    fn user(external_ids: Vec<(&str, &str)>) -> User {
+        User {
+            id: DieselUlid::generate().to_string(),
+            external_ids: external_ids
+                .into_iter()
+                .map(|(idp, id)| ExternalId {
+                    external_id: id.to_string(),
+                    idp: idp.to_string(),
+                })
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_identity_index() {
+        let cache = Cache::new();
+        let alice = user(vec![
+            ("https://idp.test", "alice"),
+            ("https://other.test", "a1"),
+        ]);
+        let bob = user(vec![("https://other.test", "alice")]);
+        cache.add_or_update_user(alice.clone()).unwrap();
+        cache.add_or_update_user(bob.clone()).unwrap();
+
+        let index = IdentityIndex::new(Duration::from_secs(60));
+        let resolve = |iss, sub| index.resolve(&cache, iss, sub);
+        assert_eq!(
+            resolve("https://idp.test", "alice").unwrap().to_string(),
+            alice.id
+        );
+        // Secondary identities are indexed too
+        assert_eq!(
+            resolve("https://other.test", "a1").unwrap().to_string(),
+            alice.id
+        );
+        // Same subject of another provider
+        assert_eq!(
+            resolve("https://other.test", "alice").unwrap().to_string(),
+            bob.id
+        );
+        assert!(matches!(
+            resolve("https://idp.test", "carol"),
+            Err(ApeError::UnregisteredUser(_, _))
+        ));
+
+        // New identities are found right after a rebuild, removed ones immediately disappear
+        let carol = user(vec![("https://idp.test", "carol")]);
+        cache.add_or_update_user(carol.clone()).unwrap();
+        assert_eq!(
+            resolve("https://idp.test", "carol").unwrap().to_string(),
+            carol.id
+        );
+        let erin = user(vec![("https://idp.test", "erin")]);
+        index.update(&erin).unwrap();
+        cache.add_or_update_user(erin.clone()).unwrap();
+        assert_eq!(
+            resolve("https://idp.test", "erin").unwrap().to_string(),
+            erin.id
+        );
+        let mut moved = alice.clone();
+        moved.external_ids.truncate(1);
+        cache.add_or_update_user(moved).unwrap();
+        assert!(resolve("https://other.test", "a1").is_err());
+
+        // Unknown identities rebuild outdated indices
+        let index = IdentityIndex::new(Duration::ZERO);
+        let dave = user(vec![("https://idp.test", "dave")]);
+        assert!(index.resolve(&cache, "https://idp.test", "dave").is_err());
+        cache.add_or_update_user(dave.clone()).unwrap();
+        assert_eq!(
+            index
+                .resolve(&cache, "https://idp.test", "dave")
+                .unwrap()
+                .to_string(),
+            dave.id
+        );
+    }
+}
@@ -0,0 +1,254 @@
+use super::oidc::{KeySource, OidcKeyStore};
+use super::validation::ClaimValidation;
+use crate::error::ApeError;
+use jsonwebtoken::{decode, decode_header, Algorithm};
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use tokio::time::MissedTickBehavior;
+
+/// Configuration of a trusted OIDC identity provider
+///
+/// - issuer: Expected value of the `iss` claim, used to select the provider
+/// - validation: Additional claim checks (audience, nbf, iat, leeway)
+/// - algorithms: Accepted signing algorithms
+/// - key_source: Where the public keys of the provider can be found
+/// - user_claim: Claim that contains the subject, mapped to an Aruna user by its external ids
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IssuerConfig {
+    pub issuer: String,
+    pub validation: ClaimValidation,
+    pub algorithms: Vec<Algorithm>,
+    pub key_source: KeySource,
+    pub user_claim: String,
+}
+
+impl IssuerConfig {
+    pub fn new(issuer: impl Into<String>, key_source: KeySource) -> Self {
+        IssuerConfig {
+            issuer: issuer.into(),
+            validation: ClaimValidation::default(),
+            algorithms: vec![Algorithm::RS256],
+            key_source,
+            user_claim: "sub".to_string(),
+        }
+    }
+}
+
+/// A trusted issuer with its cached keys
+pub(crate) struct Issuer {
+    pub config: IssuerConfig,
+    pub keys: OidcKeyStore,
+}
+
+impl Issuer {
+    pub fn new(config: IssuerConfig) -> Self {
+        Issuer {
+            keys: OidcKeyStore::new(config.key_source.clone()),
+            config,
+        }
+    }
+
+    /// Validates the token and returns the value of the configured user claim
+    pub async fn validate(&self, token: &str) -> Result<String, ApeError> {
+        let header = decode_header(token)?;
+        if !self.config.algorithms.contains(&header.alg) {
+            return Err(ApeError::TokenMalformed(format!(
+                "Algorithm {:?} not allowed for issuer {}",
+                header.alg, self.config.issuer
+            )));
+        }
+        let key = self.keys.get_key(header.kid.as_deref()).await?;
+
+        let validation = self
+            .config
+            .validation
+            .to_validation(header.alg, &self.config.issuer);
+        let claims =
+            decode::<HashMap<String, serde_json::Value>>(token, &key.key, &validation)?.claims;
+        self.config
+            .validation
+            .check_iat(claims.get("iat").and_then(|v| v.as_u64()))?;
+        claims
+            .get(&self.config.user_claim)
+            .and_then(|v| v.as_str())
+            .map(|v| v.to_string())
+            .ok_or_else(|| {
+                ApeError::TokenMalformed(format!("Missing claim: {}", self.config.user_claim))
+            })
+    }
+}
+
+/// Refreshes the keys of all issuers every `ttl` until the task is aborted
+pub(crate) fn spawn_key_refresh(
+    issuers: Arc<HashMap<String, Issuer>>,
+    ttl: Duration,
+) -> JoinHandle<()> {
+    spawn_interval(ttl, move || {
+        let issuers = issuers.clone();
+        async move {
+            for issuer in issuers.values() {
+                // Failures are tracked in the refresh status, the last good keys are kept
+                let _ = issuer.keys.refresh().await;
+            }
+        }
+    })
+}
+
+/// Runs the task immediately and then every `period`, late runs are delayed
+fn spawn_interval<F, Fut>(period: Duration, mut task: F) -> JoinHandle<()>
+where
+    F: FnMut() -> Fut + Send + 'static,
+    Fut: Future<Output = ()> + Send,
+{
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(period);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            task().await;
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::token::oidc::tests::{generate_ec_key, serve_json};
+    use jsonwebtoken::{encode, Header};
+    use std::sync::Mutex;
+
+    #[tokio::test]
+    async fn test_issuer_validation() {
+        let (key, jwk) = generate_ec_key("key");
+        let docs = Arc::new(Mutex::new(HashMap::new()));
+        let (url, _) = serve_json(docs.clone()).await;
+        docs.lock().unwrap().insert(
+            "/certs".to_string(),
+            serde_json::json!({ "keys": [jwk] }).to_string(),
+        );
+
+        let mut config =
+            IssuerConfig::new("https://idp.test", KeySource::Jwks(format!("{url}/certs")));
+        config.algorithms = vec![Algorithm::ES256];
+        config.validation.audiences = vec!["aruna".to_string()];
+        config.user_claim = "preferred_username".to_string();
+        let issuer = Issuer::new(config);
+
+        let mut header = Header::new(Algorithm::ES256);
+        header.kid = Some("key".to_string());
+        let sign = |claims: serde_json::Value| encode(&header, &claims, &key).unwrap();
+
+        let valid = sign(serde_json::json!({
+            "iss": "https://idp.test", "aud": "aruna", "sub": "1",
+            "preferred_username": "alice", "exp": 10000000000u64
+        }));
+        assert_eq!(issuer.validate(&valid).await.unwrap(), "alice");
+
+        let wrong_aud = sign(serde_json::json!({
+            "iss": "https://idp.test", "aud": "other", "preferred_username": "alice",
+            "exp": 10000000000u64
+        }));
+        assert!(matches!(
+            issuer.validate(&wrong_aud).await,
+            Err(ApeError::InvalidAudience)
+        ));
+
+        let missing_aud = sign(serde_json::json!({
+            "iss": "https://idp.test", "preferred_username": "alice",
+            "exp": 10000000000u64
+        }));
+        assert!(matches!(
+            issuer.validate(&missing_aud).await,
+            Err(ApeError::InvalidAudience)
+        ));
+
+        let wrong_iss = sign(serde_json::json!({
+            "iss": "https://other.test", "aud": "aruna", "preferred_username": "alice",
+            "exp": 10000000000u64
+        }));
+        assert!(matches!(
+            issuer.validate(&wrong_iss).await,
+            Err(ApeError::InvalidIssuer)
+        ));
+
+        let not_yet_valid = sign(serde_json::json!({
+            "iss": "https://idp.test", "aud": "aruna", "preferred_username": "alice",
+            "exp": 10000000000u64, "nbf": 9000000000u64
+        }));
+        assert!(matches!(
+            issuer.validate(&not_yet_valid).await,
+            Err(ApeError::TokenNotYetValid)
+        ));
+
+        let future_iat = sign(serde_json::json!({
+            "iss": "https://idp.test", "aud": "aruna", "preferred_username": "alice",
+            "exp": 10000000000u64, "iat": 9000000000u64
+        }));
+        assert!(matches!(
+            issuer.validate(&future_iat).await,
+            Err(ApeError::IssuedInFuture)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_background_refresh() {
+        let (_, jwk) = generate_ec_key("key");
+        let docs = Arc::new(Mutex::new(HashMap::new()));
+        let (url, counter) = serve_json(docs.clone()).await;
+        docs.lock().unwrap().insert(
+            "/certs".to_string(),
+            serde_json::json!({ "keys": [jwk] }).to_string(),
+        );
+
+        let config = IssuerConfig::new("https://idp.test", KeySource::Jwks(format!("{url}/certs")));
+        let issuers = Arc::new(HashMap::from([(
+            "https://idp.test".to_string(),
+            Issuer::new(config),
+        )]));
+        let task = spawn_key_refresh(issuers.clone(), Duration::from_secs(3600));
+        // The first refresh starts immediately
+        for _ in 0..500 {
+            if issuers["https://idp.test"]
+                .keys
+                .status()
+                .last_success
+                .is_some()
+            {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        task.abort();
+
+        assert_eq!(*counter.lock().unwrap(), 1);
+        let status = issuers["https://idp.test"].keys.status();
+        assert!(status.last_success.is_some());
+        assert!(status.last_error.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_refresh_interval() {
+        tokio::time::pause();
+        let start = tokio::time::Instant::now();
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let mut runs = 0;
+        let task = spawn_interval(Duration::from_secs(60), move || {
+            runs += 1;
+            tx.send(start.elapsed().as_secs()).unwrap();
+            // The second run takes longer than the period
+            let duration = Duration::from_secs(if runs == 2 { 90 } else { 0 });
+            async move { tokio::time::sleep(duration).await }
+        });
+
+        assert_eq!(rx.recv().await, Some(0));
+        assert_eq!(rx.recv().await, Some(60));
+        // Missed runs start once the previous one finished, the next one a full period later
+        assert_eq!(rx.recv().await, Some(150));
+        assert_eq!(rx.recv().await, Some(210));
+        task.abort();
+    }
+}
@@ -0,0 +1,355 @@
+use super::oidc::{KeySource, OidcKeyStore};
+use super::token_handler::ARUNA_ISSUER;
+use crate::error::ApeError;
+use aruna_cache::notifications::NotificationCache;
+use aruna_cache::structs::PubKey;
+use async_trait::async_trait;
+use jsonwebtoken::{Algorithm, DecodingKey};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+
+/// Component that owns the private part of a key
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum KeyOwner {
+    #[default]
+    Server,
+    DataProxy,
+}
+
+/// A public key together with the algorithm tokens signed by it must use
+#[derive(Clone)]
+pub struct VerificationKey {
+    pub algorithm: Algorithm,
+    pub key: DecodingKey,
+    pub owner: KeyOwner,
+}
+
+impl VerificationKey {
+    pub fn new(algorithm: Algorithm, key: DecodingKey) -> Self {
+        VerificationKey {
+            algorithm,
+            key,
+            owner: KeyOwner::Server,
+        }
+    }
+
+    pub fn with_owner(mut self, owner: KeyOwner) -> Self {
+        self.owner = owner;
+        self
+    }
+
+    /// Parses a PEM encoded public key for the declared algorithm,
+    /// keys of another type than the algorithm requires are rejected
+    pub fn from_pem(algorithm: Algorithm, pem: &[u8]) -> Result<Self, ApeError> {
+        let key = match algorithm {
+            Algorithm::EdDSA => DecodingKey::from_ed_pem(pem),
+            Algorithm::ES256 | Algorithm::ES384 => DecodingKey::from_ec_pem(pem),
+            Algorithm::RS256
+            | Algorithm::RS384
+            | Algorithm::RS512
+            | Algorithm::PS256
+            | Algorithm::PS384
+            | Algorithm::PS512 => DecodingKey::from_rsa_pem(pem),
+            Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512 => {
+                return Err(ApeError::Internal(format!(
+                    "Algorithm {algorithm:?} can not be used with public keys"
+                )))
+            }
+        }
+        .map_err(|e| ApeError::Internal(format!("Invalid {algorithm:?} public key: {e}")))?;
+        Ok(VerificationKey::new(algorithm, key))
+    }
+}
+
+/// Source of the public keys that are used to verify tokens
+///
+/// Keys are looked up by the `kid` of the token header and the issuer of the token.
+#[async_trait]
+pub trait KeyProvider: Send + Sync {
+    async fn get_key(&self, kid: &str, issuer: &str) -> Result<VerificationKey, ApeError>;
+}
+
+/// Uses the Aruna server and data proxy keys of the notification cache
+///
+/// The cache only stores the PEM of each key, its algorithm must be declared:
+/// keys are Ed25519 (EdDSA) keys unless another algorithm is declared for their kid.
+pub struct CacheKeyProvider {
+    cache: Arc<NotificationCache>,
+    algorithms: HashMap<String, Algorithm>,
+}
+
+impl CacheKeyProvider {
+    pub fn new(cache: Arc<NotificationCache>) -> Self {
+        CacheKeyProvider {
+            cache,
+            algorithms: HashMap::new(),
+        }
+    }
+
+    /// Declares the algorithm of a key that is not an Ed25519 key
+    pub fn with_algorithm(mut self, kid: impl Into<String>, algorithm: Algorithm) -> Self {
+        self.algorithms.insert(kid.into(), algorithm);
+        self
+    }
+}
+
+#[async_trait]
+impl KeyProvider for CacheKeyProvider {
+    async fn get_key(&self, kid: &str, issuer: &str) -> Result<VerificationKey, ApeError> {
+        if issuer != ARUNA_ISSUER {
+            return Err(ApeError::UnknownIssuer(issuer.to_string()));
+        }
+        let key = self
+            .cache
+            .cache
+            .pubkeys
+            .get(
+                &kid.parse::<i32>()
+                    .map_err(|_| ApeError::UnknownKey(kid.to_string()))?,
+            )
+            .ok_or_else(|| ApeError::UnknownKey(kid.to_string()))?
+            .clone();
+        let algorithm = self
+            .algorithms
+            .get(kid)
+            .copied()
+            .unwrap_or(Algorithm::EdDSA);
+        from_pub_key(key, algorithm)
+    }
+}
+
+/// Converts a key of the cache, data proxy keys can only sign delegation tokens
+fn from_pub_key(key: PubKey, algorithm: Algorithm) -> Result<VerificationKey, ApeError> {
+    let (pem, owner) = match key {
+        PubKey::DataProxy(k) => (k, KeyOwner::DataProxy),
+        PubKey::Server(k) => (k, KeyOwner::Server),
+    };
+    Ok(VerificationKey::from_pem(algorithm, pem.as_bytes())?.with_owner(owner))
+}
+
+/// Keys that are stored in memory, keyed by issuer and kid
+#[derive(Default)]
+pub struct MemoryKeyProvider {
+    keys: RwLock<HashMap<(String, String), VerificationKey>>,
+}
+
+impl MemoryKeyProvider {
+    pub fn new() -> Self {
+        MemoryKeyProvider::default()
+    }
+
+    pub fn insert(&self, issuer: &str, kid: &str, key: VerificationKey) -> Result<(), ApeError> {
+        self.keys
+            .write()
+            .map_err(|_| ApeError::Internal("Poisoned key store".to_string()))?
+            .insert((issuer.to_string(), kid.to_string()), key);
+        Ok(())
+    }
+
+    pub fn remove(&self, issuer: &str, kid: &str) -> Result<(), ApeError> {
+        self.keys
+            .write()
+            .map_err(|_| ApeError::Internal("Poisoned key store".to_string()))?
+            .remove(&(issuer.to_string(), kid.to_string()));
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl KeyProvider for MemoryKeyProvider {
+    async fn get_key(&self, kid: &str, issuer: &str) -> Result<VerificationKey, ApeError> {
+        self.keys
+            .read()
+            .map_err(|_| ApeError::Internal("Poisoned key store".to_string()))?
+            .get(&(issuer.to_string(), kid.to_string()))
+            .cloned()
+            .ok_or_else(|| ApeError::UnknownKey(kid.to_string()))
+    }
+}
+
+/// A PEM encoded public key on disk
+///
+/// - issuer: Issuer of the tokens signed by this key
+/// - kid: Key id that is referenced in the token header
+/// - algorithm: Algorithm of the key
+/// - owner: Whether the key belongs to the server or a data proxy
+/// - path: Location of the PEM file
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PemKeyFile {
+    pub issuer: String,
+    pub kid: String,
+    pub algorithm: Algorithm,
+    pub owner: KeyOwner,
+    pub path: PathBuf,
+}
+
+/// Static keys that are read once from PEM files
+pub struct PemKeyProvider {
+    keys: HashMap<(String, String), VerificationKey>,
+}
+
+impl PemKeyProvider {
+    pub fn load(files: Vec<PemKeyFile>) -> Result<Self, ApeError> {
+        let mut keys = HashMap::new();
+        for file in files {
+            let pem = std::fs::read(&file.path).map_err(|e| {
+                ApeError::Internal(format!("Unable to read {}: {e}", file.path.display()))
+            })?;
+            keys.insert(
+                (file.issuer, file.kid),
+                VerificationKey::from_pem(file.algorithm, &pem)?.with_owner(file.owner),
+            );
+        }
+        Ok(PemKeyProvider { keys })
+    }
+}
+
+#[async_trait]
+impl KeyProvider for PemKeyProvider {
+    async fn get_key(&self, kid: &str, issuer: &str) -> Result<VerificationKey, ApeError> {
+        self.keys
+            .get(&(issuer.to_string(), kid.to_string()))
+            .cloned()
+            .ok_or_else(|| ApeError::UnknownKey(kid.to_string()))
+    }
+}
+
+/// Keys of a single issuer that are fetched from a JWKS url
+pub struct JwksKeyProvider {
+    issuer: String,
+    store: OidcKeyStore,
+}
+
+impl JwksKeyProvider {
+    pub fn new(issuer: impl Into<String>, source: KeySource) -> Self {
+        JwksKeyProvider {
+            issuer: issuer.into(),
+            store: OidcKeyStore::new(source),
+        }
+    }
+
+    pub fn store(&self) -> &OidcKeyStore {
+        &self.store
+    }
+}
+
+#[async_trait]
+impl KeyProvider for JwksKeyProvider {
+    async fn get_key(&self, kid: &str, issuer: &str) -> Result<VerificationKey, ApeError> {
+        if issuer != self.issuer {
+            return Err(ApeError::UnknownIssuer(issuer.to_string()));
+        }
+        self.store.get_key(Some(kid)).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::token::oidc::tests::{generate_ec_key, serve_json};
+    use base64::engine::general_purpose;
+    use base64::Engine;
+    use diesel_ulid::DieselUlid;
+    use std::sync::Mutex;
+
+    /// PEM encoded Ed25519 public key
+    fn ed_pem() -> String {
+        let der = [
+            &[
+                0x30, 0x2a, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x03, 0x21, 0x00,
+            ][..],
+            &[7; 32],
+        ]
+        .concat();
+        format!(
+            "-----BEGIN PUBLIC KEY-----\n{}\n-----END PUBLIC KEY-----\n",
+            general_purpose::STANDARD.encode(der)
+        )
+    }
+
+    #[tokio::test]
+    async fn test_key_providers() {
+        let (_, jwk) = generate_ec_key("key");
+        let docs = Arc::new(Mutex::new(HashMap::new()));
+        let (url, _) = serve_json(docs.clone()).await;
+        docs.lock().unwrap().insert(
+            "/certs".to_string(),
+            serde_json::json!({ "keys": [jwk] }).to_string(),
+        );
+        let jwks =
+            JwksKeyProvider::new("https://idp.test", KeySource::Jwks(format!("{url}/certs")));
+        let key = jwks.get_key("key", "https://idp.test").await.unwrap();
+        assert_eq!(key.algorithm, Algorithm::ES256);
+        assert!(matches!(
+            jwks.get_key("key", "https://other.test").await,
+            Err(ApeError::UnknownIssuer(_))
+        ));
+
+        let memory = MemoryKeyProvider::new();
+        memory.insert("aruna", "1", key).unwrap();
+        assert!(memory.get_key("1", "aruna").await.is_ok());
+        assert!(matches!(
+            memory.get_key("1", "other").await,
+            Err(ApeError::UnknownKey(_))
+        ));
+        memory.remove("aruna", "1").unwrap();
+        assert!(memory.get_key("1", "aruna").await.is_err());
+
+        assert!(matches!(
+            VerificationKey::from_pem(Algorithm::HS256, b"secret"),
+            Err(ApeError::Internal(_))
+        ));
+
+        // Keys of the cache keep their owner
+        let pem = ed_pem();
+        let proxy = from_pub_key(PubKey::DataProxy(pem.clone()), Algorithm::EdDSA).unwrap();
+        assert_eq!(proxy.owner, KeyOwner::DataProxy);
+        assert_eq!(proxy.algorithm, Algorithm::EdDSA);
+        assert_eq!(
+            from_pub_key(PubKey::Server(pem.clone()), Algorithm::EdDSA)
+                .unwrap()
+                .owner,
+            KeyOwner::Server
+        );
+
+        // Declared algorithms must match the key type
+        assert!(from_pub_key(PubKey::Server(pem.clone()), Algorithm::PS256).is_err());
+        assert!(from_pub_key(PubKey::Server(pem), Algorithm::ES256).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_pem_key_provider() {
+        let dir = std::env::temp_dir().join(format!("aruna-keys-{}", DieselUlid::generate()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("proxy.pem");
+        std::fs::write(&path, ed_pem()).unwrap();
+        let file = |algorithm| PemKeyFile {
+            issuer: ARUNA_ISSUER.to_string(),
+            kid: "proxy".to_string(),
+            algorithm,
+            owner: KeyOwner::DataProxy,
+            path: path.clone(),
+        };
+
+        let keys = PemKeyProvider::load(vec![file(Algorithm::EdDSA)]).unwrap();
+        let key = keys.get_key("proxy", ARUNA_ISSUER).await.unwrap();
+        assert_eq!(key.algorithm, Algorithm::EdDSA);
+        assert_eq!(key.owner, KeyOwner::DataProxy);
+        assert!(matches!(
+            keys.get_key("proxy", "other").await,
+            Err(ApeError::UnknownKey(_))
+        ));
+
+        // The declared algorithm must match the key
+        assert!(PemKeyProvider::load(vec![file(Algorithm::ES256)]).is_err());
+        assert!(PemKeyProvider::load(vec![file(Algorithm::RS256)]).is_err());
+        let missing = PemKeyFile {
+            path: dir.join("missing.pem"),
+            ..file(Algorithm::EdDSA)
+        };
+        assert!(PemKeyProvider::load(vec![missing]).is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,116 @@
+use super::token_handler::{ArunaTokenClaims, Audience, ARUNA_ISSUER};
+use crate::ape::structs::ResWithPerm;
+use crate::error::ApeError;
+use diesel_ulid::DieselUlid;
+use jsonwebtoken::{encode, get_current_timestamp, Algorithm, EncodingKey, Header};
+use std::time::Duration;
+
+/// `exp` of tokens bound to a stored token without a custom expiry (9999-12-31),
+/// these still expire with the stored token
+const NO_EXPIRY: u64 = 253402300799;
+
+/// Signs Aruna claims with a registered key, shared by all token minting types
+pub(crate) struct ClaimSigner {
+    kid: String,
+    algorithm: Algorithm,
+    key: EncodingKey,
+    audience: Option<String>,
+}
+
+impl ClaimSigner {
+    pub fn new(kid: impl Into<String>, algorithm: Algorithm, key: EncodingKey) -> Self {
+        ClaimSigner {
+            kid: kid.into(),
+            algorithm,
+            key,
+            audience: None,
+        }
+    }
+
+    pub fn set_audience(&mut self, audience: impl Into<String>) {
+        self.audience = Some(audience.into());
+    }
+
+    /// Signs the claims with a unique `jti`, the configured audience and `iat` of now
+    pub fn sign(
+        &self,
+        sub: String,
+        uid: Option<String>,
+        exp: u64,
+        perms: Option<Vec<ResWithPerm>>,
+    ) -> Result<String, ApeError> {
+        let claims = ArunaTokenClaims {
+            iss: ARUNA_ISSUER.to_string(),
+            sub,
+            uid,
+            exp: exp as usize,
+            aud: self.audience.clone().map(Audience::Single),
+            nbf: None,
+            iat: Some(get_current_timestamp() as usize),
+            perms,
+            jti: Some(DieselUlid::generate().to_string()),
+        };
+        let mut header = Header::new(self.algorithm);
+        header.kid = Some(self.kid.to_string());
+        Ok(encode(&header, &claims, &self.key)?)
+    }
+}
+
+/// Mints Aruna tokens with the Ed25519 key of the server
+///
+/// The `kid` must reference the server key that is registered for verification,
+/// all minted tokens are accepted by `TokenHandler::process_token`.
+pub struct TokenIssuer {
+    signer: ClaimSigner,
+}
+
+impl TokenIssuer {
+    pub fn from_ed_pem(kid: impl Into<String>, pem: &[u8]) -> Result<Self, ApeError> {
+        Ok(TokenIssuer {
+            signer: ClaimSigner::new(kid, Algorithm::EdDSA, EncodingKey::from_ed_pem(pem)?),
+        })
+    }
+
+    /// Uses a PKCS#8 DER encoded Ed25519 private key
+    pub fn from_ed_der(kid: impl Into<String>, der: &[u8]) -> Self {
+        TokenIssuer {
+            signer: ClaimSigner::new(kid, Algorithm::EdDSA, EncodingKey::from_ed_der(der)),
+        }
+    }
+
+    /// Sets the `aud` of all minted tokens, required if the verifier checks audiences
+    pub fn with_audience(mut self, audience: impl Into<String>) -> Self {
+        self.signer.set_audience(audience);
+        self
+    }
+
+    /// Signs a token for the user
+    ///
+    /// - token_id: Stored token this token is bound to, checked on every request
+    /// - expires_in: Lifetime of the token, only optional for tokens bound to a stored token
+    /// - perms: Embedded permissions that narrow the permissions of the user
+    ///
+    pub fn sign(
+        &self,
+        user_id: DieselUlid,
+        token_id: Option<DieselUlid>,
+        expires_in: Option<Duration>,
+        perms: Option<Vec<ResWithPerm>>,
+    ) -> Result<String, ApeError> {
+        let exp = match (expires_in, token_id) {
+            (Some(lifetime), _) => get_current_timestamp() + lifetime.as_secs(),
+            (None, Some(_)) => NO_EXPIRY,
+            (None, None) => {
+                return Err(ApeError::InvalidArgument(
+                    "Tokens without a stored token require an expiry".to_string(),
+                ))
+            }
+        };
+        // Tokens bound to a stored token use the token id as subject
+        let (sub, uid) = match token_id {
+            Some(token_id) => (token_id.to_string(), Some(user_id.to_string())),
+            None => (user_id.to_string(), None),
+        };
+        self.signer.sign(sub, uid, exp, perms)
+    }
+}
@@ -1 +1,9 @@
+pub mod delegation;
+pub mod identity;
+pub mod issuer;
+pub mod key_provider;
+pub mod minting;
+pub mod oidc;
+pub mod token_cache;
 pub mod token_handler;
+pub mod validation;
@@ -0,0 +1,426 @@
+use super::key_provider::VerificationKey;
+use crate::error::ApeError;
+use jsonwebtoken::jwk::{AlgorithmParameters, EllipticCurve, Jwk, PublicKeyUse};
+use jsonwebtoken::{Algorithm, DecodingKey};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::RwLock;
+use std::time::{Duration, Instant, SystemTime};
+
+/// Minimum time between two refetches that are triggered by unknown kids
+const MIN_REFETCH_INTERVAL: Duration = Duration::from_secs(10);
+/// Maximum duration of a single request to the provider
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Where the public keys of an OIDC provider can be found
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeySource {
+    /// Keycloak realm info endpoint, contains a single `public_key`
+    RealmInfo(String),
+    /// Issuer url, keys are discovered via `/.well-known/openid-configuration`
+    Discovery(String),
+    /// Url of a JWKS endpoint
+    Jwks(String),
+}
+
+#[derive(Deserialize, Debug)]
+struct KeyCloakResponse {
+    #[serde(alias = "realm")]
+    _realm: String,
+    public_key: String,
+    #[serde(alias = "token-service")]
+    _token_service: String,
+    #[serde(alias = "account-service")]
+    _account_service: String,
+    #[serde(alias = "tokens-not-before")]
+    _tokens_not_before: i64,
+}
+
+#[derive(Deserialize, Debug)]
+struct OpenIdConfiguration {
+    jwks_uri: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct RawJwkSet {
+    keys: Vec<serde_json::Value>,
+}
+
+#[derive(Default)]
+struct KeySet {
+    keys: HashMap<String, VerificationKey>,
+    // Key without kid, used for all tokens (Keycloak realm info)
+    default: Option<VerificationKey>,
+    last_fetch: Option<Instant>,
+}
+
+/// Result of the last key refreshes of one provider
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RefreshStatus {
+    pub last_success: Option<SystemTime>,
+    pub last_failure: Option<SystemTime>,
+    pub last_error: Option<String>,
+}
+
+/// Caches all public keys of one OIDC provider
+///
+/// Keys are fetched lazily and refetched if a token references an unknown kid,
+/// this allows providers to rotate their keys without a restart.
+pub struct OidcKeyStore {
+    source: KeySource,
+    // Clients that can not be built fail every fetch, the error shows up in the status
+    client: Result<reqwest::Client, String>,
+    keys: RwLock<KeySet>,
+    status: RwLock<RefreshStatus>,
+}
+
+impl OidcKeyStore {
+    pub fn new(source: KeySource) -> Self {
+        let client = reqwest::Client::builder()
+            .timeout(FETCH_TIMEOUT)
+            .build()
+            .map_err(|e| format!("Unable to build the HTTP client: {e}"));
+        OidcKeyStore {
+            source,
+            client,
+            keys: RwLock::new(KeySet::default()),
+            status: RwLock::new(RefreshStatus::default()),
+        }
+    }
+
+    pub fn source(&self) -> &KeySource {
+        &self.source
+    }
+
+    pub fn status(&self) -> RefreshStatus {
+        match self.status.read() {
+            Ok(status) => status.clone(),
+            Err(poisoned) => poisoned.into_inner().clone(),
+        }
+    }
+
+    /// Returns the key for a kid, refetches all keys if the kid is unknown
+    pub async fn get_key(&self, kid: Option<&str>) -> Result<VerificationKey, ApeError> {
+        if let Some(key) = self.lookup(kid)? {
+            return Ok(key);
+        }
+        if self.may_refetch()? {
+            self.refresh().await?;
+            if let Some(key) = self.lookup(kid)? {
+                return Ok(key);
+            }
+        }
+        Err(ApeError::UnknownKey(kid.unwrap_or_default().to_string()))
+    }
+
+    /// Fetches all keys from the provider and replaces the cached ones,
+    /// the last good keys are kept if the provider can not be reached
+    pub async fn refresh(&self) -> Result<(), ApeError> {
+        let result = self.fetch().await;
+        let mut status = match self.status.write() {
+            Ok(status) => status,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        let (keys, default) = match result {
+            Ok(keys) => {
+                status.last_success = Some(SystemTime::now());
+                status.last_error = None;
+                keys
+            }
+            Err(e) => {
+                status.last_failure = Some(SystemTime::now());
+                status.last_error = Some(e.to_string());
+                return Err(ApeError::Internal(e.to_string()));
+            }
+        };
+        let mut lock = self
+            .keys
+            .write()
+            .map_err(|_| ApeError::Internal("Poisoned key store".to_string()))?;
+        *lock = KeySet {
+            keys,
+            default,
+            last_fetch: Some(Instant::now()),
+        };
+        Ok(())
+    }
+
+    fn lookup(&self, kid: Option<&str>) -> Result<Option<VerificationKey>, ApeError> {
+        let lock = self
+            .keys
+            .read()
+            .map_err(|_| ApeError::Internal("Poisoned key store".to_string()))?;
+        if let Some(key) = kid.and_then(|kid| lock.keys.get(kid)) {
+            return Ok(Some(key.clone()));
+        }
+        if lock.default.is_some() {
+            return Ok(lock.default.clone());
+        }
+        if kid.is_none() && lock.keys.len() == 1 {
+            return Ok(lock.keys.values().next().cloned());
+        }
+        Ok(None)
+    }
+
+    fn may_refetch(&self) -> Result<bool, ApeError> {
+        let lock = self
+            .keys
+            .read()
+            .map_err(|_| ApeError::Internal("Poisoned key store".to_string()))?;
+        Ok(lock
+            .last_fetch
+            .map(|last| last.elapsed() >= MIN_REFETCH_INTERVAL)
+            .unwrap_or(true))
+    }
+
+    #[allow(clippy::type_complexity)]
+    async fn fetch(
+        &self,
+    ) -> anyhow::Result<(HashMap<String, VerificationKey>, Option<VerificationKey>)> {
+        let client = self.client.as_ref().map_err(|e| anyhow::anyhow!("{e}"))?;
+        let jwks_uri = match &self.source {
+            KeySource::RealmInfo(url) => {
+                let resp = client
+                    .get(url)
+                    .send()
+                    .await?
+                    .json::<KeyCloakResponse>()
+                    .await?;
+                let key = DecodingKey::from_rsa_pem(
+                    format!(
+                        "{}\n{}\n{}",
+                        "-----BEGIN PUBLIC KEY-----", resp.public_key, "-----END PUBLIC KEY-----"
+                    )
+                    .as_bytes(),
+                )?;
+                return Ok((
+                    HashMap::new(),
+                    Some(VerificationKey::new(Algorithm::RS256, key)),
+                ));
+            }
+            KeySource::Discovery(issuer) => {
+                let url = format!(
+                    "{}/.well-known/openid-configuration",
+                    issuer.trim_end_matches('/')
+                );
+                client
+                    .get(url)
+                    .send()
+                    .await?
+                    .json::<OpenIdConfiguration>()
+                    .await?
+                    .jwks_uri
+            }
+            KeySource::Jwks(url) => url.to_string(),
+        };
+
+        let jwks = client
+            .get(jwks_uri)
+            .send()
+            .await?
+            .json::<RawJwkSet>()
+            .await?;
+        let mut keys = HashMap::new();
+        let mut default = None;
+        for raw in jwks.keys {
+            // Skip keys that can not be parsed instead of failing the whole set
+            let Ok(jwk) = serde_json::from_value::<Jwk>(raw) else {
+                continue;
+            };
+            // Symmetric keys must never be used to verify tokens
+            if let AlgorithmParameters::OctetKey(_) = jwk.algorithm {
+                continue;
+            }
+            if jwk.common.public_key_use == Some(PublicKeyUse::Encryption) {
+                continue;
+            }
+            let Some(algorithm) = jwk_algorithm(&jwk) else {
+                continue;
+            };
+            let key = VerificationKey::new(algorithm, DecodingKey::from_jwk(&jwk)?);
+            match jwk.common.key_id {
+                Some(kid) => {
+                    keys.insert(kid, key);
+                }
+                None => default = default.or(Some(key)),
+            }
+        }
+        Ok((keys, default))
+    }
+}
+
+/// Uses the declared `alg` of a JWK or derives it from the key type
+fn jwk_algorithm(jwk: &Jwk) -> Option<Algorithm> {
+    if let Some(alg) = jwk.common.algorithm {
+        return Some(alg);
+    }
+    match &jwk.algorithm {
+        AlgorithmParameters::RSA(_) => Some(Algorithm::RS256),
+        AlgorithmParameters::EllipticCurve(params) => match params.curve {
+            EllipticCurve::P256 => Some(Algorithm::ES256),
+            EllipticCurve::P384 => Some(Algorithm::ES384),
+            _ => None,
+        },
+        AlgorithmParameters::OctetKeyPair(params) => match params.curve {
+            EllipticCurve::Ed25519 => Some(Algorithm::EdDSA),
+            _ => None,
+        },
+        AlgorithmParameters::OctetKey(_) => None,
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use base64::engine::general_purpose;
+    use base64::Engine;
+    use jsonwebtoken::{decode, encode, Algorithm, EncodingKey, Header, Validation};
+    use ring::rand::SystemRandom;
+    use ring::signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_FIXED_SIGNING};
+    use std::sync::{Arc, Mutex};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    /// Generates a P-256 key and returns the encoding key and its JWK
+    pub(crate) fn generate_ec_key(kid: &str) -> (EncodingKey, serde_json::Value) {
+        let pkcs8 =
+            EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &SystemRandom::new())
+                .unwrap();
+        let pair =
+            EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, pkcs8.as_ref()).unwrap();
+        // Uncompressed point: 0x04 || x || y
+        let point = pair.public_key().as_ref();
+        let jwk = serde_json::json!({
+            "kty": "EC",
+            "crv": "P-256",
+            "use": "sig",
+            "alg": "ES256",
+            "kid": kid,
+            "x": general_purpose::URL_SAFE_NO_PAD.encode(&point[1..33]),
+            "y": general_purpose::URL_SAFE_NO_PAD.encode(&point[33..65]),
+        });
+        (EncodingKey::from_ec_der(pkcs8.as_ref()), jwk)
+    }
+
+    /// Serves fixed json documents per path and counts the requests
+    pub(crate) async fn serve_json(
+        docs: Arc<Mutex<HashMap<String, String>>>,
+    ) -> (String, Arc<Mutex<usize>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let counter = Arc::new(Mutex::new(0));
+        let count = counter.clone();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut buf = vec![0; 4096];
+                let n = stream.read(&mut buf).await.unwrap_or_default();
+                let request = String::from_utf8_lossy(&buf[..n]).to_string();
+                let path = request.split_whitespace().nth(1).unwrap_or("/").to_string();
+                *count.lock().unwrap() += 1;
+                let body = docs.lock().unwrap().get(&path).cloned();
+                let response = match body {
+                    Some(body) => format!(
+                        "HTTP/1.1 200 OK\r\ncontent-type: application/json\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+                        body.len(),
+                        body
+                    ),
+                    None => "HTTP/1.1 404 Not Found\r\ncontent-length: 0\r\nconnection: close\r\n\r\n"
+                        .to_string(),
+                };
+                let _ = stream.write_all(response.as_bytes()).await;
+            }
+        });
+        (url, counter)
+    }
+
+    fn sign(kid: &str, key: &EncodingKey) -> String {
+        let mut header = Header::new(Algorithm::ES256);
+        header.kid = Some(kid.to_string());
+        encode(
+            &header,
+            &serde_json::json!({"sub": "user", "exp": 10000000000u64}),
+            key,
+        )
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_jwks_discovery_and_rotation() {
+        let (key_1, jwk_1) = generate_ec_key("key-1");
+        let (key_2, jwk_2) = generate_ec_key("key-2");
+        let docs = Arc::new(Mutex::new(HashMap::new()));
+        let (url, counter) = serve_json(docs.clone()).await;
+        docs.lock().unwrap().insert(
+            "/.well-known/openid-configuration".to_string(),
+            serde_json::json!({ "jwks_uri": format!("{url}/certs") }).to_string(),
+        );
+        docs.lock().unwrap().insert(
+            "/certs".to_string(),
+            serde_json::json!({ "keys": [jwk_1, {"kty": "oct", "kid": "hmac", "k": "c2VjcmV0"}] })
+                .to_string(),
+        );
+
+        let store = OidcKeyStore::new(KeySource::Discovery(url));
+        let token_1 = sign("key-1", &key_1);
+        let dec = store.get_key(Some("key-1")).await.unwrap();
+        assert!(
+            decode::<serde_json::Value>(&token_1, &dec.key, &Validation::new(dec.algorithm))
+                .is_ok()
+        );
+        assert_eq!(*counter.lock().unwrap(), 2);
+
+        // Cached keys do not trigger a refetch, symmetric keys are ignored
+        store.get_key(Some("key-1")).await.unwrap();
+        assert!(store.get_key(Some("hmac")).await.is_err());
+        assert_eq!(*counter.lock().unwrap(), 2);
+
+        // Provider rotates its keys
+        docs.lock().unwrap().insert(
+            "/certs".to_string(),
+            serde_json::json!({ "keys": [jwk_2] }).to_string(),
+        );
+        store.keys.write().unwrap().last_fetch = None;
+        let token_2 = sign("key-2", &key_2);
+        let dec = store.get_key(Some("key-2")).await.unwrap();
+        assert!(
+            decode::<serde_json::Value>(&token_2, &dec.key, &Validation::new(dec.algorithm))
+                .is_ok()
+        );
+        assert!(matches!(
+            store.get_key(Some("key-1")).await,
+            Err(ApeError::UnknownKey(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_refresh_keeps_last_good_keys() {
+        let (_, jwk) = generate_ec_key("key");
+        let docs = Arc::new(Mutex::new(HashMap::new()));
+        let (url, _) = serve_json(docs.clone()).await;
+        docs.lock().unwrap().insert(
+            "/certs".to_string(),
+            serde_json::json!({ "keys": [jwk] }).to_string(),
+        );
+
+        let store = OidcKeyStore::new(KeySource::Jwks(format!("{url}/certs")));
+        store.refresh().await.unwrap();
+        let status = store.status();
+        assert!(status.last_success.is_some());
+        assert!(status.last_failure.is_none());
+
+        docs.lock().unwrap().clear();
+        assert!(store.refresh().await.is_err());
+        let status = store.status();
+        assert!(status.last_failure.is_some());
+        assert!(status.last_error.is_some());
+        assert!(store.get_key(Some("key")).await.is_ok());
+
+        // A successful refresh clears the last error
+        docs.lock().unwrap().insert(
+            "/certs".to_string(),
+            serde_json::json!({ "keys": [jwk] }).to_string(),
+        );
+        store.refresh().await.unwrap();
+        assert_eq!(store.status().last_error, None);
+    }
+}
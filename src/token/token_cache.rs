@@ -0,0 +1,158 @@
+use super::token_handler::TokenInfo;
+use crate::error::ApeError;
+use jsonwebtoken::get_current_timestamp;
+use ring::digest::{digest, SHA256};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+pub(crate) type TokenHash = [u8; 32];
+
+/// Outcome of the signature and claim validation of a token
+///
+/// Verified tokens can be reused until they expire or reach the maximum age,
+/// the identity mapping, revocation and stored token checks are repeated for every request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum VerifiedToken {
+    Aruna(TokenInfo),
+    External { issuer: String, subject: String },
+}
+
+struct Entry {
+    token: VerifiedToken,
+    exp: u64,
+    verified_at: Instant,
+}
+
+#[derive(Default)]
+struct Entries {
+    map: HashMap<TokenHash, Entry>,
+    /// Hashes in insertion order, evicted first in first out
+    order: VecDeque<TokenHash>,
+}
+
+/// Bounded cache of verified tokens keyed by the SHA-256 hash of the token
+pub(crate) struct VerifiedTokenCache {
+    capacity: usize,
+    max_age: Duration,
+    entries: Mutex<Entries>,
+}
+
+impl VerifiedTokenCache {
+    pub fn new(capacity: usize, max_age: Duration) -> Self {
+        VerifiedTokenCache {
+            capacity,
+            max_age,
+            entries: Mutex::default(),
+        }
+    }
+
+    /// Returns the verified token until its `exp` or until it was verified `max_age` ago
+    pub fn get(&self, token: &str) -> Result<Option<VerifiedToken>, ApeError> {
+        let hash = hash_token(token);
+        let mut entries = self.lock()?;
+        match entries.map.get(&hash) {
+            Some(entry)
+                if entry.exp > get_current_timestamp()
+                    && entry.verified_at.elapsed() < self.max_age =>
+            {
+                Ok(Some(entry.token.clone()))
+            }
+            Some(_) => {
+                entries.map.remove(&hash);
+                Ok(None)
+            }
+            None => Ok(None),
+        }
+    }
+
+    pub fn insert(&self, token: &str, verified: VerifiedToken, exp: u64) -> Result<(), ApeError> {
+        if self.capacity == 0 {
+            return Ok(());
+        }
+        let hash = hash_token(token);
+        let mut entries = self.lock()?;
+        while !entries.map.contains_key(&hash) && entries.map.len() >= self.capacity {
+            let Some(oldest) = entries.order.pop_front() else {
+                break;
+            };
+            entries.map.remove(&oldest);
+        }
+        if entries
+            .map
+            .insert(
+                hash,
+                Entry {
+                    token: verified,
+                    exp,
+                    verified_at: Instant::now(),
+                },
+            )
+            .is_none()
+        {
+            entries.order.push_back(hash);
+        }
+        // Expired entries leave stale hashes behind
+        if entries.order.len() > 2 * self.capacity {
+            let Entries { map, order } = &mut *entries;
+            order.retain(|hash| map.contains_key(hash));
+        }
+        Ok(())
+    }
+
+    pub fn clear(&self) -> Result<(), ApeError> {
+        *self.lock()? = Entries::default();
+        Ok(())
+    }
+
+    fn lock(&self) -> Result<MutexGuard<'_, Entries>, ApeError> {
+        self.entries
+            .lock()
+            .map_err(|_| ApeError::Internal("Poisoned token cache".to_string()))
+    }
+}
+
+pub(crate) fn hash_token(token: &str) -> TokenHash {
+    let mut hash = [0; 32];
+    hash.copy_from_slice(digest(&SHA256, token.as_bytes()).as_ref());
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use diesel_ulid::DieselUlid;
+
+    #[test]
+    fn test_verified_token_cache() {
+        let tokens = VerifiedTokenCache::new(2, Duration::from_secs(60));
+        let info = |user_id| {
+            VerifiedToken::Aruna(TokenInfo {
+                user_id: Some(user_id),
+                ..Default::default()
+            })
+        };
+        let (a, b) = (DieselUlid::generate(), DieselUlid::generate());
+        let exp = get_current_timestamp() + 60;
+
+        tokens.insert("a", info(a), exp).unwrap();
+        tokens.insert("b", info(b), exp).unwrap();
+        assert_eq!(tokens.get("a").unwrap(), Some(info(a)));
+        assert_eq!(tokens.get("c").unwrap(), None);
+
+        // The oldest token is evicted first
+        tokens.insert("c", info(b), exp).unwrap();
+        assert_eq!(tokens.get("a").unwrap(), None);
+        assert!(tokens.get("b").unwrap().is_some());
+        assert!(tokens.get("c").unwrap().is_some());
+
+        // Expired tokens are never returned
+        tokens.insert("expired", info(a), 0).unwrap();
+        assert_eq!(tokens.get("expired").unwrap(), None);
+
+        // Tokens are verified again after the maximum age
+        let tokens = VerifiedTokenCache::new(2, Duration::ZERO);
+        tokens.insert("a", info(a), exp).unwrap();
+        assert_eq!(tokens.get("a").unwrap(), None);
+    }
+}
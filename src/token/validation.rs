@@ -0,0 +1,75 @@
+use crate::error::ApeError;
+use jsonwebtoken::{get_current_timestamp, Algorithm, Validation};
+
+/// Claim checks that are applied in addition to the signature and `exp`
+///
+/// - audiences: Accepted `aud` values, empty means the audience is not checked
+/// - leeway: Allowed clock skew in seconds for `exp`, `nbf` and `iat`
+/// - validate_nbf: Reject tokens that are used before their `nbf`
+/// - validate_iat: Reject tokens that were issued in the future
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClaimValidation {
+    pub audiences: Vec<String>,
+    pub leeway: u64,
+    pub validate_nbf: bool,
+    pub validate_iat: bool,
+}
+
+impl Default for ClaimValidation {
+    fn default() -> Self {
+        ClaimValidation {
+            audiences: vec![],
+            leeway: 60,
+            validate_nbf: true,
+            validate_iat: true,
+        }
+    }
+}
+
+impl ClaimValidation {
+    pub(crate) fn to_validation(&self, alg: Algorithm, issuer: &str) -> Validation {
+        let mut validation = Validation::new(alg);
+        validation.leeway = self.leeway;
+        validation.validate_nbf = self.validate_nbf;
+        validation.set_issuer(&[issuer]);
+        if !self.audiences.is_empty() {
+            // Tokens without `aud` skip the audience check unless it is required
+            validation.set_audience(&self.audiences);
+            validation.required_spec_claims.insert("aud".to_string());
+        }
+        validation
+    }
+
+    pub(crate) fn check_iat(&self, iat: Option<u64>) -> Result<(), ApeError> {
+        match iat {
+            Some(iat) if self.validate_iat && iat > get_current_timestamp() + self.leeway => {
+                Err(ApeError::IssuedInFuture)
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_iat() {
+        let validation = ClaimValidation::default();
+        let now = get_current_timestamp();
+        assert!(validation.check_iat(None).is_ok());
+        assert!(validation.check_iat(Some(now)).is_ok());
+        assert!(validation.check_iat(Some(now + 30)).is_ok());
+        assert!(matches!(
+            validation.check_iat(Some(now + 3600)),
+            Err(ApeError::IssuedInFuture)
+        ));
+        let disabled = ClaimValidation {
+            validate_iat: false,
+            ..Default::default()
+        };
+        assert!(disabled.check_iat(Some(now + 3600)).is_ok());
+    }
+}